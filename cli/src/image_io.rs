@@ -0,0 +1,46 @@
+use std::path::Path;
+use visionmagic::visioncortex::ColorImage;
+use crate::Result;
+
+/// Load a PNG or JPEG as RGBA
+pub fn load(path: &Path) -> Result<ColorImage> {
+    let image = image::open(path)?.to_rgba8();
+    let (width, height) = image.dimensions();
+    Ok(ColorImage {
+        pixels: image.into_raw(),
+        width: width as usize,
+        height: height as usize,
+    })
+}
+
+pub fn save(path: &Path, image: &ColorImage) -> Result<()> {
+    image::save_buffer(
+        path, &image.pixels, image.width as u32, image.height as u32, image::ColorType::Rgba8
+    )?;
+    Ok(())
+}
+
+/// Load a mask as RGBA where alpha is 255 for masked (opaque light) pixels and 0 otherwise
+pub fn load_mask(path: &Path, width: u32, height: u32) -> Result<Vec<u8>> {
+    let mask = image::open(path)?.to_rgba8();
+    if mask.dimensions() != (width, height) {
+        return Err("mask must have the same size as the input".into());
+    }
+    let mut pixels = mask.into_raw();
+    for px in pixels.chunks_exact_mut(4) {
+        let light = px[0] as u32 + px[1] as u32 + px[2] as u32 > 3 * 127;
+        let opaque = px[3] >= 128;
+        px[3] = if light && opaque { 255 } else { 0 };
+    }
+    Ok(pixels)
+}
+
+/// 32 bits -> 24 bits
+pub fn to_rgb(image: &ColorImage) -> Vec<u8> {
+    image.pixels.chunks_exact(4).flat_map(|px| px[0..3].iter().copied()).collect()
+}
+
+/// 24 bits -> 32 bits
+pub fn to_rgba(buf: &[u8]) -> Vec<u8> {
+    buf.chunks_exact(3).flat_map(|px| [px[0], px[1], px[2], 255]).collect()
+}
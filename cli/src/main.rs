@@ -0,0 +1,371 @@
+//! Command-line interface to the Impression algorithms
+use std::path::{Path, PathBuf};
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use visionmagic::fmm::{painter::Painter, smoother::Smoother};
+use visionmagic::color_metric::{Cie76, Ciede2000, ColorMetric, WeightedRgb};
+use visionmagic::edge::EdgeParams;
+use visionmagic::importance::{ImportanceMap, Protection};
+use visionmagic::simplification::PathMode;
+use visionmagic::stats::Srm;
+use visionmagic::visioncortex::ColorImage;
+use visionmagic::aggregation::{MergeOrder, MergePolicy, Paint};
+use visionmagic::segmentation::VoteLadder;
+use visionmagic::clustering::Connectivity;
+use visionmagic::{aggregation, clustering, segmentation, simplification};
+use visionmagic::{Aggregation, Clustering, Processor, Segmentation, Simplification};
+
+mod image_io;
+mod render;
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+#[derive(Parser)]
+#[command(name = "visionmagic", version, about = "Collection of vision & graphics algorithms")]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Simplify an image into flat shapes
+    Simplify(SimplifyArgs),
+    /// Segment an image into regions of similar color
+    Segment(SegmentArgs),
+    /// Repair (inpaint) the masked area of an image
+    Repair(RepairArgs),
+}
+
+#[derive(Args)]
+struct Io {
+    /// Input image (PNG or JPEG)
+    #[arg(short, long)]
+    input: PathBuf,
+    /// Output file; the format follows the extension
+    #[arg(short, long)]
+    output: PathBuf,
+}
+
+#[derive(Args)]
+struct SimplifyArgs {
+    #[command(flatten)]
+    io: Io,
+    /// Valid range is 0~65535. Ratio of how many nodes we retain from the image tree
+    #[arg(long, default_value_t = 32768)]
+    fidelity: u32,
+    /// Valid range is 0~65535. Ratio of how many points we use to outline each shape
+    #[arg(long, default_value_t = 32768)]
+    shape_details: u32,
+    /// Valid range is 1~256. More levels means finer gradient
+    #[arg(long, default_value_t = 16)]
+    color_levels: u32,
+    /// Number of decimal places of SVG coordinates; full precision if omitted
+    #[arg(long)]
+    precision: Option<u32>,
+    /// Fit shapes with Bezier curves instead of polygons
+    #[arg(long)]
+    spline: bool,
+    /// Connect pixels touching at a corner, which keeps thin diagonal strokes together
+    #[arg(long)]
+    diagonal: bool,
+}
+
+#[derive(Args)]
+struct SegmentArgs {
+    #[command(flatten)]
+    io: Io,
+    /// Allowed color difference between shapes in same segment
+    #[arg(long, default_value_t = 0.5)]
+    deviation: f64,
+    /// Minimum segment size in area
+    #[arg(long, default_value_t = 64 * 64)]
+    min_size: u32,
+    /// Merge rules tuned for the kind of content
+    #[arg(long, value_enum, default_value_t = Preset::Standard)]
+    preset: Preset,
+    /// Which small segment merges next
+    #[arg(long, value_enum, default_value_t = Order::Index)]
+    merge_order: Order,
+    /// Paint each segment with the mean color of its area, instead of that of the part
+    /// the others merged into
+    #[arg(long)]
+    mean_color: bool,
+    /// Color difference metric; deviation is measured in its unit
+    #[arg(long, value_enum, default_value_t = Metric::Hsv)]
+    metric: Metric,
+    /// Penalty on merging across image edges, per unit of edge strength (0~1)
+    #[arg(long)]
+    edge: Option<f64>,
+    /// Never merge across edges stronger than this (0~1)
+    #[arg(long, requires = "edge")]
+    edge_threshold: Option<f64>,
+    /// Merge by the Statistical Region Merging test of this complexity instead of deviation;
+    /// higher gives more segments
+    #[arg(long)]
+    srm: Option<f64>,
+    /// Mask image of the same size; segments mostly within its opaque light pixels are kept
+    /// however small
+    #[arg(long)]
+    protect: Option<PathBuf>,
+    /// Connect pixels touching at a corner, which keeps thin diagonal strokes together
+    #[arg(long)]
+    diagonal: bool,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Preset {
+    /// The default rules
+    Standard,
+    /// Natural images
+    Photo,
+    /// Flat-colored artwork
+    Illustration,
+    /// A few flat colors with fine details
+    Logo,
+}
+
+impl Preset {
+    fn ladder(self) -> VoteLadder {
+        match self {
+            Self::Standard => VoteLadder::standard(),
+            Self::Photo => VoteLadder::photo(),
+            Self::Illustration => VoteLadder::illustration(),
+            Self::Logo => VoteLadder::logo(),
+        }
+    }
+
+    fn policy(self) -> MergePolicy {
+        match self {
+            Self::Standard => MergePolicy::standard(),
+            Self::Photo => MergePolicy::photo(),
+            Self::Illustration => MergePolicy::illustration(),
+            Self::Logo => MergePolicy::logo(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Order {
+    /// In the order segments were found, each once
+    Index,
+    /// Smallest first, revisiting segments as they grow
+    Smallest,
+    /// Closest in color to its neighbour first
+    Similar,
+}
+
+impl Order {
+    fn build(self) -> MergeOrder {
+        match self {
+            Self::Index => MergeOrder::Index,
+            Self::Smallest => MergeOrder::Smallest,
+            Self::Similar => MergeOrder::Similar,
+        }
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Metric {
+    /// Weighted hue, saturation & value differences
+    Hsv,
+    /// CIELAB ΔE76 / 100
+    Cie76,
+    /// CIEDE2000 ΔE00 / 100
+    Ciede2000,
+    /// Weighted RGB distance
+    Rgb,
+}
+
+impl Metric {
+    /// `None` keeps the Processor's default, which is HSV with its own weighting
+    fn build(self) -> Option<Box<dyn ColorMetric>> {
+        match self {
+            Self::Hsv => None,
+            Self::Cie76 => Some(Box::new(Cie76)),
+            Self::Ciede2000 => Some(Box::new(Ciede2000)),
+            Self::Rgb => Some(Box::new(WeightedRgb::default())),
+        }
+    }
+}
+
+#[derive(Args)]
+struct RepairArgs {
+    #[command(flatten)]
+    io: Io,
+    /// Mask image of the same size; opaque light pixels mark the area to repair
+    #[arg(short, long)]
+    mask: PathBuf,
+    /// Radius of the smoothing applied over the repaired area
+    #[arg(long, default_value_t = 10)]
+    blurriness: u32,
+}
+
+#[derive(PartialEq)]
+enum Format {
+    Png,
+    Svg,
+}
+
+fn main() {
+    let cli = Cli::parse();
+    let result = match cli.command {
+        Command::Simplify(args) => simplify(args),
+        Command::Segment(args) => segment(args),
+        Command::Repair(args) => repair(args),
+    };
+    if let Err(error) = result {
+        eprintln!("error: {}", error);
+        std::process::exit(1);
+    }
+}
+
+fn output_format(path: &Path) -> Result<Format> {
+    let extension = path.extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| ext.to_ascii_lowercase());
+    match extension.as_deref() {
+        Some("png") => Ok(Format::Png),
+        Some("svg") => Ok(Format::Svg),
+        _ => Err(format!("unsupported output format: {}", path.display()).into()),
+    }
+}
+
+fn connectivity(diagonal: bool) -> Connectivity {
+    if diagonal { Connectivity::Eight } else { Connectivity::Four }
+}
+
+fn run<P: Processor>(processor: &mut P, input: P::Input) -> Result<P::Output> {
+    processor.input(input)?;
+    while !processor.tick() {}
+    Ok(processor.output()?)
+}
+
+fn simplify(args: SimplifyArgs) -> Result<()> {
+    let format = output_format(&args.io.output)?;
+    let image = image_io::load(&args.io.input)?;
+    let (width, height) = (image.width, image.height);
+
+    let mut clustering = Clustering::new();
+    clustering.config(clustering::Params {
+        color_levels: args.color_levels,
+        connectivity: connectivity(args.diagonal),
+        ..Default::default()
+    })?;
+    let clusters = run(&mut clustering, image)?;
+
+    let mut simplifier = Simplification::new();
+    simplifier.config(simplification::Params {
+        fidelity: args.fidelity,
+        shape_details: args.shape_details,
+        path_mode: if args.spline { PathMode::Spline } else { PathMode::Polygon },
+    })?;
+    let shapes = run(&mut simplifier, clusters)?;
+
+    match format {
+        Format::Png => {
+            let (background, _) = simplifier.get_background();
+            image_io::save(&args.io.output, &render::rasterize(width, height, &background, &shapes)?)
+        },
+        Format::Svg => Ok(std::fs::write(&args.io.output, simplifier.to_svg(&shapes, args.precision)?)?),
+    }
+}
+
+fn segment(args: SegmentArgs) -> Result<()> {
+    if output_format(&args.io.output)? != Format::Png {
+        return Err("segment only supports PNG output".into());
+    }
+    let image = image_io::load(&args.io.input)?;
+    let edge = args.edge.map(|weight| EdgeParams {
+        weight,
+        threshold: args.edge_threshold.unwrap_or(f64::INFINITY),
+    });
+    let srm = args.srm.map(|complexity| Srm {
+        complexity,
+        ..Default::default()
+    });
+    let protection = match args.protect.as_ref() {
+        Some(path) => {
+            let mask = image_io::load_mask(path, image.width as u32, image.height as u32)?;
+            Some(Protection::new(ImportanceMap {
+                pixels: mask.chunks_exact(4).map(|px| px[3] as f32 / 255.0).collect(),
+                width: image.width,
+                height: image.height,
+            }))
+        },
+        None => None,
+    };
+
+    let clustering_params = || clustering::Params {
+        hierarchical: 64,
+        connectivity: connectivity(args.diagonal),
+        ..Default::default()
+    };
+    let clustering = || -> Result<Clustering> {
+        let mut clustering = Clustering::new();
+        clustering.config(clustering_params())?;
+        Ok(clustering)
+    };
+    let mut segmenter = Segmentation::new();
+    let mut params = segmentation::Params {
+        deviation: args.deviation,
+        ladder: args.preset.ladder(),
+        edge,
+        srm,
+        protection: protection.clone(),
+        ..Default::default()
+    };
+    if let Some(metric) = args.metric.build() {
+        params.metric = metric;
+    }
+    segmenter.config(params)?;
+    let mut aggregation = Aggregation::new();
+    let mut params = aggregation::Params {
+        deviation: args.deviation,
+        min_size: args.min_size,
+        policy: args.preset.policy(),
+        order: args.merge_order.build(),
+        // that of the clustering before it, as the clusters do not record it
+        connectivity: clustering_params().connectivity,
+        paint: if args.mean_color { Paint::Mean } else { Paint::Winner },
+        edge,
+        srm,
+        // aggregation sees the segments re-clustered, so measure on the original
+        source: Some(image.clone()),
+        protection,
+        ..Default::default()
+    };
+    if let Some(metric) = args.metric.build() {
+        params.metric = metric;
+    }
+    aggregation.config(params)?;
+    let mut pipeline = clustering()?
+        .chain(segmenter)
+        .chain(clustering()?)
+        .chain(aggregation);
+    let segmented = run(&mut pipeline, image)?;
+    image_io::save(&args.io.output, &segmented)
+}
+
+fn repair(args: RepairArgs) -> Result<()> {
+    if output_format(&args.io.output)? != Format::Png {
+        return Err("repair only supports PNG output".into());
+    }
+    let image = image_io::load(&args.io.input)?;
+    let (width, height) = (image.width as u32, image.height as u32);
+    if width <= 2 * args.blurriness || height <= 2 * args.blurriness {
+        return Err("blurriness is too large for the image".into());
+    }
+    let mask = image_io::load_mask(&args.mask, width, height)?;
+
+    let mut painter = Painter::new(image_io::to_rgb(&image), &mask, width, height);
+    while painter.progress != 100 {
+        painter = painter.paint();
+    }
+    let smoothed = Smoother::new(painter.im.buf, width, height, args.blurriness).smooth(&mask);
+    let repaired = ColorImage {
+        pixels: image_io::to_rgba(&smoothed.im.buf),
+        width: width as usize,
+        height: height as usize,
+    };
+    image_io::save(&args.io.output, &repaired)
+}
@@ -0,0 +1,62 @@
+use tiny_skia::{FillRule, Paint, PathBuilder, Pixmap, Transform};
+use visionmagic::simplification::OutputUnit;
+use visionmagic::visioncortex::{Color, ColorImage, CompoundPath, CompoundPathElement};
+use crate::Result;
+
+/// Paint the simplified shapes over the background, in stacking order
+pub fn rasterize(width: usize, height: usize, background: &Color, shapes: &[OutputUnit]) -> Result<ColorImage> {
+    let mut pixmap = Pixmap::new(width as u32, height as u32).ok_or("invalid image size")?;
+    pixmap.fill(tiny_skia::Color::from_rgba8(background.r, background.g, background.b, 255));
+    let mut paint = Paint::default();
+    for shape in shapes.iter() {
+        if let Some(path) = to_skia_path(&shape.path) {
+            paint.set_color_rgba8(shape.color.r, shape.color.g, shape.color.b, 255);
+            pixmap.fill_path(&path, &paint, FillRule::EvenOdd, Transform::identity(), None);
+        }
+    }
+    Ok(ColorImage {
+        pixels: pixmap.take(),
+        width,
+        height,
+    })
+}
+
+fn to_skia_path(paths: &CompoundPath) -> Option<tiny_skia::Path> {
+    let mut builder = PathBuilder::new();
+    for path in paths.iter() {
+        match path {
+            CompoundPathElement::PathI32(path) => {
+                polygon(&mut builder, path.iter().map(|p| (p.x as f32, p.y as f32)));
+            },
+            CompoundPathElement::PathF64(path) => {
+                polygon(&mut builder, path.iter().map(|p| (p.x as f32, p.y as f32)));
+            },
+            CompoundPathElement::Spline(spline) => {
+                let points = &spline.points;
+                if points.is_empty() {
+                    continue;
+                }
+                builder.move_to(points[0].x as f32, points[0].y as f32);
+                for curve in points[1..].chunks_exact(3) {
+                    builder.cubic_to(
+                        curve[0].x as f32, curve[0].y as f32,
+                        curve[1].x as f32, curve[1].y as f32,
+                        curve[2].x as f32, curve[2].y as f32,
+                    );
+                }
+                builder.close();
+            },
+        }
+    }
+    builder.finish()
+}
+
+fn polygon(builder: &mut PathBuilder, mut points: impl Iterator<Item = (f32, f32)>) {
+    if let Some((x, y)) = points.next() {
+        builder.move_to(x, y);
+        for (x, y) in points {
+            builder.line_to(x, y);
+        }
+        builder.close();
+    }
+}
@@ -1,18 +1,42 @@
 //! Processor to remove small clusters by merging into larger ones
-use std::collections::HashSet;
+//!
+//! Aggregates are kept in a region adjacency graph, so that merging costs in proportion to
+//! the number of neighbours rather than the area.
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BinaryHeap};
 use visioncortex::{Color, ColorImage};
 use visioncortex::color_clusters::Clusters;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
 
+use crate::Error;
+use crate::clustering::Connectivity;
+use crate::color_metric::{ColorMetric, Hsv};
+use crate::edge::{BoundaryStrength, EdgeParams, Edges};
+use crate::importance::Protection;
+use crate::stats::{self, RegionStats, Srm};
+use crate::region::RegionMap;
 use crate::pipeline::Processor as ProcessorTrait;
+#[cfg(feature = "serde")]
+use crate::serde_remote::ColorDef;
 
 #[derive(Default)]
 pub struct Processor {
     params: Params,
+    /// kept to start over on reconfiguration
+    clusters: Option<Input>,
     width: u32,
     height: u32,
+    /// initial aggregate of each pixel
     indices: Vec<AggregateIndex>,
     aggregates: Vec<Aggregate>,
     counter: usize,
+    edges: Option<Edges>,
+    /// aggregates that may merge, by priority then index; entries go stale as aggregates merge.
+    /// Built on the first tick in a priority [`MergeOrder`]
+    queue: Option<BinaryHeap<Reverse<(u64, AggregateIndex)>>>,
+    /// merges done plus the length of the queue when built, to estimate progress
+    queued: usize,
 }
 
 /// [`Clusters`]
@@ -22,19 +46,110 @@ pub type Input = Clusters;
 pub type Output = ColorImage;
 
 pub struct Params {
-    /// Allowed color difference between shapes in same aggregate
+    /// Allowed color difference between shapes in same aggregate, in the unit of `metric`
     pub deviation: f64,
     /// Minimum patch size in area
     pub min_size: u32,
+    /// When a patch merges, by its size relative to `min_size` and color difference relative to `deviation`
+    pub policy: MergePolicy,
+    /// Which patch gets to merge next
+    pub order: MergeOrder,
+    /// Which patches are neighbours; take it from the [`clustering::Params`] that built the
+    /// input, as the clusters do not record it
+    ///
+    /// [`clustering::Params`]: crate::clustering::Params
+    pub connectivity: Connectivity,
+    /// Color each aggregate is painted with in the output
+    pub paint: Paint,
+    /// How color difference is measured
+    pub metric: Box<dyn ColorMetric>,
+    /// If set, penalise or refuse merges across strong edges in the image, except for patches
+    /// `policy` merges regardless of color. Prepared on input; after restore, only if `source` is given
+    pub edge: Option<EdgeParams>,
+    /// If set, merge patches into the most similar neighbour passing this test, instead of by
+    /// `deviation`; patches `policy` merges regardless of color are still merged
+    pub srm: Option<Srm>,
+    /// Image to measure edges and texture on, instead of the input's own; must be of the same size.
+    /// When aggregating a re-clustering of flat segments, this should be the original image
+    pub source: Option<ColorImage>,
+    /// If set, penalise or refuse merges of important patches, even those `policy` merges
+    /// regardless of color; the map must be of the same size as the input
+    pub protection: Option<Protection>,
 }
 
+/// A patch merges into its closest neighbour if any of the rules allows
+#[derive(Clone, Debug, PartialEq)]
+pub struct MergePolicy {
+    pub rules: Vec<MergeRule>,
+}
+
+/// Allows patches up to a size to merge with a neighbour up to a color difference
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MergeRule {
+    /// Covers patches smaller than `min_size` times this; `None` for any size
+    pub below: Option<f64>,
+    /// Allows color difference less than `deviation` times this; `None` for any difference
+    pub within: Option<f64>,
+}
+
+/// Order in which patches merge
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MergeOrder {
+    /// In the order of the input's clusters, each patch once
+    #[default]
+    Index,
+    /// The smallest patch that may merge first, over and over, so that fragments left by
+    /// earlier merges are revisited; independent of the order of clusters
+    Smallest,
+    /// The patch closest to the neighbour it would merge into first, over and over
+    Similar,
+}
+
+/// Color of an aggregate in the output. Merges are decided on the winner color either way
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Paint {
+    /// That of the patch the others merged into
+    #[default]
+    Winner,
+    /// Mean over the area of the colors of the patches merged
+    Mean,
+}
+
+/// In-flight state of a [`Processor`]; params are not included and have to be configured again
+/// before restore
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Snapshot {
+    width: u32,
+    height: u32,
+    indices: Vec<AggregateIndex>,
+    aggregates: Vec<Aggregate>,
+    counter: usize,
+    /// merges expected when the queue was built; 0 if it was not
+    queued: usize,
+}
+
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 struct Aggregate {
-    indices: Vec<u32>,
+    /// in pixels; 0 once merged into another
+    area: usize,
+    #[cfg_attr(feature = "serde", serde(with = "ColorDef"))]
     color: Color,
+    /// sum of r, g and b of the colors of the patches merged, weighted by their area
+    sum: [u64; 3],
+    /// if testing with `srm`
+    stats: Option<RegionStats>,
+    /// summed over the pixels, if protecting
+    importance: f64,
+    /// adjacent aggregates, with the boundary shared (whose strength is only summed if measuring edges)
+    neighbours: BTreeMap<AggregateIndex, BoundaryStrength>,
+    /// the aggregate merged into, or itself
+    into: AggregateIndex,
 }
 
 #[derive(Copy, Clone, Default, Eq, Ord, Hash, PartialEq, PartialOrd)]
-struct AggregateIndex(pub usize);
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+struct AggregateIndex(pub u32);
 
 const ZERO: AggregateIndex = AggregateIndex(0);
 
@@ -43,8 +158,125 @@ impl Default for Params {
         Self {
             deviation: 1.0,
             min_size: 64 * 64,
+            policy: MergePolicy::default(),
+            order: MergeOrder::default(),
+            connectivity: Connectivity::default(),
+            paint: Paint::default(),
+            metric: Box::new(Hsv::new(1.5, 0.75, 1.25)),
+            edge: None,
+            srm: None,
+            source: None,
+            protection: None,
+        }
+    }
+}
+
+impl Params {
+    fn validate(&self) -> Result<(), Error> {
+        if !(self.deviation >= 0.0 && self.deviation.is_finite()) {
+            return Err(Error::InvalidParams("deviation"));
+        }
+        self.policy.validate()?;
+        if let Some(edge) = self.edge.as_ref() {
+            edge.validate()?;
+        }
+        if let Some(srm) = self.srm.as_ref() {
+            srm.validate()?;
+        }
+        if let Some(protection) = self.protection.as_ref() {
+            protection.validate()?;
+        }
+        Ok(())
+    }
+
+    /// error unless `aggregates` carry the stats that `srm` needs
+    fn check_stats(&self, aggregates: &[Aggregate]) -> Result<(), Error> {
+        if self.srm.is_some() && aggregates.iter().any(|agg| agg.area > 0 && agg.stats.is_none()) {
+            return Err(Error::InvalidParams("srm"));
+        }
+        Ok(())
+    }
+}
+
+impl Default for MergePolicy {
+    fn default() -> Self {
+        Self::standard()
+    }
+}
+
+impl MergePolicy {
+    /// The default, as aggregation has always done. Patches below a sixteenth of `min_size`
+    /// merge regardless of color; otherwise the smaller the patch, the more color difference
+    /// it is allowed, from twice `deviation` down to a quarter of it for patches of any size.
+    /// The presets are set relative to these rules
+    pub fn standard() -> Self {
+        Self::scaled(1.0 / 16.0, 1.0)
+    }
+
+    /// For natural images. Textures leave many small patches, so patches are allowed twice the
+    /// standard color difference, and those below a quarter of `min_size` merge regardless
+    pub fn photo() -> Self {
+        Self::scaled(1.0 / 4.0, 2.0)
+    }
+
+    /// For flat-colored artwork. Specks, e.g. from anti-aliasing, still go as in the standard
+    /// rules, but patches are allowed only half the standard color difference, so that shapes
+    /// keep their color
+    pub fn illustration() -> Self {
+        Self::scaled(1.0 / 16.0, 0.5)
+    }
+
+    /// For a few flat colors with fine details. Only patches below a sixty-fourth of
+    /// `min_size` merge regardless of color, and the others are allowed a quarter of the
+    /// standard color difference
+    pub fn logo() -> Self {
+        Self::scaled(1.0 / 64.0, 0.25)
+    }
+
+    /// the standard rules with patches below `speck` times `min_size` merging regardless of
+    /// color, and every allowed color difference multiplied by `tolerance`
+    fn scaled(speck: f64, tolerance: f64) -> Self {
+        Self {
+            rules: vec![
+                MergeRule::new(Some(speck), None),
+                MergeRule::new(Some(1.0), Some(tolerance)),
+                MergeRule::new(Some(1.0 / 4.0), Some(2.0 * tolerance)),
+                MergeRule::new(Some(4.0), Some(0.5 * tolerance)),
+                MergeRule::new(None, Some(0.25 * tolerance)),
+            ],
         }
     }
+
+    fn validate(&self) -> Result<(), Error> {
+        let valid = |factor: Option<f64>| factor.is_none_or(|factor| factor >= 0.0 && factor.is_finite());
+        if !self.rules.iter().all(|rule| valid(rule.below) && valid(rule.within)) {
+            return Err(Error::InvalidParams("policy"));
+        }
+        Ok(())
+    }
+
+    /// whether a patch of `area` may merge with a neighbour `diff` away
+    fn allows(&self, area: usize, min_size: u32, diff: f64, deviation: f64) -> bool {
+        self.rules.iter().any(|rule| {
+            rule.covers(area, min_size) && rule.within.is_none_or(|within| diff < deviation * within)
+        })
+    }
+
+    /// whether a patch of `area` may merge whatever the color difference
+    fn regardless(&self, area: usize, min_size: u32) -> bool {
+        self.rules.iter().any(|rule| rule.within.is_none() && rule.covers(area, min_size))
+    }
+}
+
+impl MergeRule {
+    pub fn new(below: Option<f64>, within: Option<f64>) -> Self {
+        Self { below, within }
+    }
+
+    fn covers(&self, area: usize, min_size: u32) -> bool {
+        // rounded down, as integer division
+        self.below.is_none_or(|below| area < (min_size as f64 * below) as usize)
+    }
 }
 
 impl ProcessorTrait for Processor {
@@ -57,111 +289,387 @@ impl ProcessorTrait for Processor {
         Self::default()
     }
 
-    fn config(&mut self, params: Params) -> bool {
+    /// configure aggregation parameters; can be reconfigured on runtime, which starts over
+    /// from the input
+    fn config(&mut self, params: Params) -> Result<(), Error> {
+        params.validate()?;
+        if self.clusters.is_none() {
+            // restored if anything, so the stats cannot be measured anew
+            params.check_stats(&self.aggregates)?;
+        }
         self.params = params;
-        if self.counter != 0 {
-            panic!("Aggregate cannot be reconfigured");
+        if let Some(clusters) = self.clusters.take() {
+            // keep the input even if the params do not fit it
+            let result = self.prepare(&clusters);
+            self.clusters = Some(clusters);
+            result?;
+        }
+        Ok(())
+    }
+
+    fn input(&mut self, input: Input) -> Result<(), Error> {
+        if input.output_len() == 0 {
+            return Err(Error::EmptyInput);
+        }
+        self.prepare(&input)?;
+        self.clusters = Some(input);
+        Ok(())
+    }
+
+    fn tick(&mut self) -> bool {
+        if self.params.order != MergeOrder::Index {
+            return self.tick_queue();
+        }
+        if self.counter < self.aggregates.len() {
+            let myselfi = AggregateIndex(self.counter as u32);
+            if let Some((otheri, _)) = self.candidate(myselfi) {
+                self.merge_into(myselfi, otheri);
+            }
+            self.counter += 1;
+        }
+        // finished on the tick visiting the last aggregate, when progress reaches 100
+        self.counter >= self.aggregates.len()
+    }
+
+    fn progress(&self) -> u32 {
+        if self.aggregates.is_empty() {
+            return 0;
+        }
+        if self.params.order == MergeOrder::Index {
+            return (100 * std::cmp::min(self.counter, self.aggregates.len()) / self.aggregates.len()) as u32;
+        }
+        match self.queue.as_ref() {
+            None => 0,
+            Some(queue) if queue.is_empty() => 100,
+            // merges may outnumber the aggregates first queued, as merging lets others merge
+            Some(_) => std::cmp::min(99, 100 * self.counter / std::cmp::max(self.queued, 1)) as u32,
+        }
+    }
+
+    /// to be called after process ends
+    fn output(&mut self) -> Result<Output, Error> {
+        if self.aggregates.is_empty() {
+            return Err(Error::WrongStage("no input"));
+        }
+        let mut image = ColorImage::new_w_h(self.width as usize, self.height as usize);
+        for (px, aggi) in self.pixels().iter().enumerate() {
+            if *aggi != ZERO {
+                let x = px % self.width as usize;
+                let y = px / self.width as usize;
+                image.set_pixel(x, y, &self.paint_color(self.get_agg(*aggi)));
+            }
+        }
+        Ok(image)
+    }
+
+}
+
+impl Processor {
+    /// capture the in-flight state
+    pub fn snapshot(&self) -> Result<Snapshot, Error> {
+        if self.aggregates.is_empty() {
+            return Err(Error::WrongStage("no input"));
+        }
+        Ok(Snapshot {
+            width: self.width,
+            height: self.height,
+            indices: self.indices.clone(),
+            aggregates: self.aggregates.clone(),
+            counter: self.counter,
+            queued: if self.queue.is_some() { self.queued } else { 0 },
+        })
+    }
+
+    /// resume from a snapshot. The input is not part of snapshots, so reconfiguring afterwards
+    /// only affects the rest of the run
+    pub fn restore(&mut self, snapshot: Snapshot) -> Result<(), Error> {
+        let len = snapshot.aggregates.len();
+        let valid = |i: &AggregateIndex| (i.0 as usize) < len;
+        let area = snapshot.width.checked_mul(snapshot.height).ok_or(Error::InvalidSnapshot)?;
+        if  snapshot.indices.len() != area as usize ||
+            !snapshot.indices.iter().all(valid) ||
+            !consistent(&snapshot.aggregates) {
+            return Err(Error::InvalidSnapshot);
+        }
+        self.params.check_stats(&snapshot.aggregates)?;
+        self.width = snapshot.width;
+        self.height = snapshot.height;
+        self.indices = snapshot.indices;
+        self.aggregates = snapshot.aggregates;
+        self.counter = snapshot.counter;
+        self.queue = None;
+        self.clusters = None;
+        self.edges = match (self.params.edge.as_ref(), self.params.source.as_ref()) {
+            // the input image is not part of snapshots
+            (Some(edge), Some(source)) => {
+                if source.width != self.width as usize || source.height != self.height as usize {
+                    return Err(Error::InvalidParams("source"));
+                }
+                Some(Edges::new(edge, &source.pixels, self.width, self.height))
+            },
+            _ => None,
+        };
+        if self.params.order != MergeOrder::Index {
+            // rebuilt now rather than on the next tick, so as to report progress in between
+            self.build_queue();
+            if snapshot.queued > 0 {
+                self.queued = snapshot.queued;
+            }
+        }
+        Ok(())
+    }
+
+    /// per-pixel aggregate labels with the table of aggregates; to be called after process ends
+    pub fn output_regions(&mut self) -> Result<RegionMap, Error> {
+        if self.aggregates.is_empty() {
+            return Err(Error::WrongStage("no input"));
+        }
+        let mut groups = vec![Vec::new(); self.aggregates.len()];
+        for (px, aggi) in self.pixels().iter().enumerate() {
+            if *aggi != ZERO {
+                groups[aggi.0 as usize].push(px as u32);
+            }
+        }
+        Ok(RegionMap::from_groups(self.width, self.height, self.aggregates.iter().zip(groups).map(|(agg, pixels)| {
+            (agg.mean(), pixels)
+        })))
+    }
+
+    /// queue every aggregate that may merge
+    fn build_queue(&mut self) {
+        let mut queue = BinaryHeap::new();
+        for i in 1..self.aggregates.len() {
+            let myselfi = AggregateIndex(i as u32);
+            if let Some(priority) = self.priority(myselfi) {
+                queue.push(Reverse((priority, myselfi)));
+            }
+        }
+        self.queued = self.counter + queue.len();
+        self.queue = Some(queue);
+    }
+
+    /// merge the first aggregate in the queue; returns true when none may merge
+    fn tick_queue(&mut self) -> bool {
+        if self.queue.is_none() {
+            self.build_queue();
+        }
+        while let Some(Reverse((priority, myselfi))) = self.queue.as_mut().and_then(|queue| queue.pop()) {
+            match self.priority(myselfi) {
+                Some(current) if current == priority => (),
+                Some(current) => {
+                    self.enqueue(myselfi, current);
+                    continue;
+                },
+                None => continue,
+            }
+            let (otheri, _) = self.candidate(myselfi).unwrap();
+            self.merge_into(myselfi, otheri);
+            // the merge changes what the merged aggregate and its neighbours may merge into
+            let mut affected: Vec<AggregateIndex> = self.get_agg(otheri).neighbours.keys().copied().collect();
+            affected.push(otheri);
+            for aggi in affected {
+                if let Some(priority) = self.priority(aggi) {
+                    self.enqueue(aggi, priority);
+                }
+            }
+            self.counter += 1;
+            // finished once none may merge, when progress reaches 100
+            return self.queue.as_ref().is_some_and(|queue| queue.is_empty());
         }
         true
     }
 
-    fn input(&mut self, input: Input) -> bool {
+    fn enqueue(&mut self, aggi: AggregateIndex, priority: u64) {
+        if let Some(queue) = self.queue.as_mut() {
+            queue.push(Reverse((priority, aggi)));
+        }
+    }
+
+    /// rank in the queue, or `None` if the aggregate may not merge
+    fn priority(&self, myselfi: AggregateIndex) -> Option<u64> {
+        let (_, distance) = self.candidate(myselfi)?;
+        match self.params.order {
+            MergeOrder::Index | MergeOrder::Smallest => Some(self.get_agg(myselfi).area() as u64),
+            MergeOrder::Similar => Some(distance as u64),
+        }
+    }
+
+    /// neighbour to merge into with its distance, if any
+    fn candidate(&self, myselfi: AggregateIndex) -> Option<(AggregateIndex, i32)> {
+        let myself = self.get_agg(myselfi);
+        if myself.area() == 0 {
+            return None;
+        }
+        // merges refused by edges rank last, and only happen to patches merged regardless of
+        // color; those refused by protection never happen
+        let mut votes: Vec<(AggregateIndex, i32)> = myself.neighbours.iter().filter_map(|(otheri, boundary)| {
+            let other = self.get_agg(*otheri);
+            let importance = myself.importance().max(other.importance());
+            if self.params.protection.as_ref().is_some_and(|protection| protection.refuses(importance)) {
+                return None;
+            }
+            Some((*otheri, self.color_distance(myself, other, boundary).unwrap_or(i32::MAX)))
+        }).collect();
+        votes.sort_by_key(|v| v.1);
+        let otheri = self.choose(myself, &votes)?;
+        votes.into_iter().find(|v| v.0 == otheri)
+    }
+
+    /// neighbour to merge into, if any, given the votes sorted by distance
+    fn choose(&self, myself: &Aggregate, votes: &[(AggregateIndex, i32)]) -> Option<AggregateIndex> {
+        let (nearest, distance) = *votes.first()?;
+        let (area, min_size) = (myself.area(), self.params.min_size);
+        if self.params.policy.regardless(area, min_size) {
+            return Some(nearest);
+        }
+        if distance == i32::MAX {
+            // refused by edges
+            return None;
+        }
+        if let Some(srm) = self.params.srm.as_ref() {
+            let area = (self.width * self.height) as usize;
+            return votes.iter()
+                .take_while(|v| v.1 != i32::MAX)
+                .find(|v| srm.test(myself.stats(), self.get_agg(v.0).stats(), area))
+                .map(|v| v.0);
+        }
+        let diff = distance as f64 / 10000.0;
+        if self.params.policy.allows(area, min_size, diff, self.params.deviation) {
+            Some(nearest)
+        } else {
+            None
+        }
+    }
+
+    /// set up the aggregates of the input, discarding any previous state
+    fn prepare(&mut self, input: &Input) -> Result<(), Error> {
         let view = input.view();
+        let pixels = stats::source_pixels(self.params.source.as_ref(), &view)?;
+        if let Some(protection) = self.params.protection.as_ref() {
+            protection.check_size(view.width, view.height)?;
+        }
+        self.edges = self.params.edge.as_ref().map(|edge| Edges::new(edge, pixels, view.width, view.height));
         self.counter = 0;
+        self.queue = None;
         self.width = view.width; 
         self.height = view.height;
         self.indices = vec![ZERO; view.cluster_indices.len()];
-        self.aggregates.push(Aggregate {
-            indices: Vec::new(),
-            color: Color::new(0,0,0),
-        });
+        self.aggregates.clear();
+        let srm = self.params.srm.is_some();
+        self.aggregates.push(Aggregate::new(ZERO, 0, Color::new(0,0,0), srm.then(RegionStats::new)));
         for cluster in view.iter() {
-            self.aggregates.push(Aggregate {
-                indices: cluster.indices.clone(),
-                color: cluster.residue_color(),
-            });
-            let myindex = AggregateIndex(self.aggregates.len() - 1);
+            let myindex = AggregateIndex(self.aggregates.len() as u32);
+            let mut aggregate = Aggregate::new(
+                myindex,
+                cluster.area(),
+                cluster.residue_color(),
+                srm.then(|| RegionStats::from_pixels(pixels, &cluster.indices)),
+            );
+            if let Some(protection) = self.params.protection.as_ref() {
+                aggregate.importance = protection.map.sum(&cluster.indices);
+            }
+            self.aggregates.push(aggregate);
             for idx in cluster.indices.iter() {
                 self.indices[*idx as usize] = myindex;
             }
         }
-        true
+        self.connect();
+        Ok(())
     }
 
-    fn tick(&mut self) -> bool {
-        if self.counter < self.aggregates.len() {
-            let myselfi = AggregateIndex(self.counter);
-            let myself = self.get_agg(myselfi);
-            if myself.area() > 0 {
-                let mut votes: Vec<(AggregateIndex, i32)> = self.neighbours_of(myselfi).iter().map(|otheri| {
-                    let other = self.get_agg(*otheri);
-                    (*otheri, Self::color_distance(myself, other))
-                }).collect();
-                votes.sort_by_key(|v| v.1);
-                if !votes.is_empty() {
-                    let diff = votes[0].1 as f64 / 10000.0;
-                    if  (myself.area() < self.params.min_size as usize / 16) ||
-                        (diff < self.params.deviation && myself.area() < self.params.min_size as usize) ||
-                        (diff < self.params.deviation * 2.0 && myself.area() < self.params.min_size as usize / 4) ||
-                        (diff < self.params.deviation / 2.0 && myself.area() < self.params.min_size as usize * 4) || 
-                        (diff < self.params.deviation / 4.0) {
-                        self.merge_into(myselfi, votes[0].0);
+    /// build the adjacency graph from the pixels
+    fn connect(&mut self) {
+        let (width, height) = (self.width as usize, self.height as usize);
+        let diagonal = self.params.connectivity == Connectivity::Eight;
+        for y in 0..height {
+            for x in 0..width {
+                let i = y * width + x;
+                if x + 1 < width {
+                    self.add_boundary(i, i + 1);
+                }
+                if y + 1 < height {
+                    self.add_boundary(i, i + width);
+                    if diagonal && x + 1 < width {
+                        self.add_boundary(i, i + width + 1);
+                    }
+                    if diagonal && x > 0 {
+                        self.add_boundary(i, i + width - 1);
                     }
                 }
             }
-            self.counter += 1;
-            false
-        } else {
-            true
         }
     }
 
-    fn progress(&self) -> u32 {
-        100
+    /// record the contact between two adjacent pixels
+    fn add_boundary(&mut self, i: usize, j: usize) {
+        let (a, b) = (self.indices[i], self.indices[j]);
+        if a == b || a == ZERO || b == ZERO {
+            return;
+        }
+        let strength = self.edges.as_ref().map_or(0.0, |edges| edges.strength(i, j));
+        self.get_agg_mut(a).neighbours.entry(b).or_default().add(strength);
+        self.get_agg_mut(b).neighbours.entry(a).or_default().add(strength);
     }
 
-    /// to be called once only after process ends
-    fn output(&mut self) -> Output {
-        let mut image = ColorImage::new_w_h(self.width as usize, self.height as usize);
-        for agg in self.aggregates.iter() {
-            for px in agg.indices.iter() {
-                let x = px % self.width;
-                let y = px / self.width;
-                image.set_pixel(x as usize, y as usize, &agg.color);
+    fn merge_into(&mut self, myselfi: AggregateIndex, otheri: AggregateIndex) {
+        let myself = self.get_agg_mut(myselfi);
+        let neighbours = std::mem::take(&mut myself.neighbours);
+        let stats = myself.stats.take();
+        let area = std::mem::replace(&mut myself.area, 0);
+        let sum = myself.sum;
+        let importance = myself.importance;
+        myself.into = otheri;
+        for (aggi, boundary) in neighbours.into_iter() {
+            if aggi == otheri {
+                continue;
             }
+            let agg = self.get_agg_mut(aggi);
+            agg.neighbours.remove(&myselfi);
+            agg.neighbours.entry(otheri).or_default().merge(&boundary);
+            self.get_agg_mut(otheri).neighbours.entry(aggi).or_default().merge(&boundary);
         }
-        image
+        let other = self.get_agg_mut(otheri);
+        other.neighbours.remove(&myselfi);
+        other.area += area;
+        for (sum, mine) in other.sum.iter_mut().zip(sum.iter()) {
+            *sum += mine;
+        }
+        if let (Some(other), Some(stats)) = (other.stats.as_mut(), stats) {
+            other.merge(&stats);
+        }
+        other.importance += importance;
     }
 
-}
-
-impl Processor {
-    fn merge_into(&mut self, myselfi: AggregateIndex, otheri: AggregateIndex) {
-        for idx in self.aggregates[myselfi.0 as usize].indices.iter() {
-            self.indices[*idx as usize] = otheri;
+    /// current aggregate of each pixel
+    fn pixels(&self) -> Vec<AggregateIndex> {
+        let mut roots: Vec<AggregateIndex> = self.aggregates.iter().map(|agg| agg.into).collect();
+        // follow each chain of merges to its end
+        for i in (0..roots.len()).rev() {
+            let mut root = roots[i];
+            while root != roots[root.0 as usize] {
+                root = roots[root.0 as usize];
+            }
+            roots[i] = root;
         }
-        let mut indices = std::mem::take(&mut self.get_agg_mut(myselfi).indices);
-        self.get_agg_mut(otheri).indices.append(&mut indices);
+        self.indices.iter().map(|aggi| roots[aggi.0 as usize]).collect()
     }
 
-    fn color_distance(myself: &Aggregate, other: &Aggregate) -> i32 {
-        let mycolor = myself.color;
-        let otcolor = other.color;
-        (10000.0 * Self::color_diff_hsv(mycolor, otcolor)) as i32
+    /// `None` if the merge is refused by edges
+    fn color_distance(&self, myself: &Aggregate, other: &Aggregate, boundary: &BoundaryStrength) -> Option<i32> {
+        let mut penalty = match self.edges.as_ref() {
+            Some(edges) => edges.penalty(boundary)?,
+            None => 0.0,
+        };
+        if let Some(protection) = self.params.protection.as_ref() {
+            penalty += protection.penalty(myself.importance().max(other.importance()));
+        }
+        Some((10000.0 * (self.params.metric.distance(myself.color, other.color) + penalty)) as i32)
     }
 
-    fn color_diff_hsv(a: Color, b: Color) -> f64 {
-        let a = a.to_hsv();
-        let b = b.to_hsv();
-        return 1.5 * wrap(a.h, b.h) + 1.25 * (a.v - b.v).abs() + 0.75 * (a.s - b.s).abs();
-
-        fn wrap(x: f64, y: f64) -> f64 {
-            let d = (x - y).abs();
-            if d < 0.5 {
-                d
-            } else {
-                1.0 - d
-            }
+    fn paint_color(&self, agg: &Aggregate) -> Color {
+        match self.params.paint {
+            Paint::Winner => agg.color,
+            Paint::Mean => agg.mean(),
         }
     }
 
@@ -172,37 +680,406 @@ impl Processor {
     fn get_agg_mut(&mut self, index: AggregateIndex) -> &mut Aggregate {
         &mut self.aggregates[index.0 as usize]
     }
+}
 
-    fn neighbours_of(&self, myselfi: AggregateIndex) -> Vec<AggregateIndex> {
-        let myself = self.get_agg(myselfi);
-        let mut neighbours = HashSet::new();
-
-        for &i in myself.indices.iter() {
-            let x = i % self.width;
-            let y = i / self.width;
-
-            for k in 0..4 {
-                let index = match k {
-                    0 => if y > 0 { self.indices[(self.width * (y - 1) + x) as usize] } else { ZERO },
-                    1 => if y < self.height - 1 { self.indices[(self.width * (y + 1) + x) as usize] } else { ZERO },
-                    2 => if x > 0 { self.indices[(self.width * y + (x - 1)) as usize] } else { ZERO },
-                    3 => if x < self.width - 1 { self.indices[(self.width * y + (x + 1)) as usize] } else { ZERO },
-                    _ => unreachable!(),
-                };
-                if index != ZERO && index != myselfi {
-                    neighbours.insert(index);
+impl Aggregate {
+    fn new(index: AggregateIndex, area: usize, color: Color, stats: Option<RegionStats>) -> Self {
+        let weighted = |c: u8| c as u64 * area as u64;
+        Self {
+            area,
+            color,
+            sum: [weighted(color.r), weighted(color.g), weighted(color.b)],
+            stats,
+            importance: 0.0,
+            neighbours: BTreeMap::new(),
+            into: index,
+        }
+    }
+
+    pub fn area(&self) -> usize {
+        self.area
+    }
+
+    /// kept by every aggregate not merged away while testing with `srm`
+    fn stats(&self) -> &RegionStats {
+        self.stats.as_ref().unwrap()
+    }
+
+    /// mean over the pixels
+    fn importance(&self) -> f64 {
+        if self.area == 0 {
+            0.0
+        } else {
+            self.importance / self.area as f64
+        }
+    }
+
+    fn mean(&self) -> Color {
+        if self.area == 0 {
+            return self.color;
+        }
+        let mean = |sum: u64| (sum / self.area as u64) as u8;
+        Color::new_rgba(mean(self.sum[0]), mean(self.sum[1]), mean(self.sum[2]), self.color.a)
+    }
+}
+
+/// whether `aggregates` are as a [`Processor`] leaves them: the first is the empty `ZERO`, every
+/// chain of merges ends at a live aggregate, and only live aggregates border one another
+fn consistent(aggregates: &[Aggregate]) -> bool {
+    const UNSEEN: u8 = 0;
+    const ON_PATH: u8 = 1;
+    const DONE: u8 = 2;
+    let live = |i: &AggregateIndex| *i != ZERO &&
+        aggregates.get(i.0 as usize).is_some_and(|agg| agg.into == *i && agg.area > 0);
+    match aggregates.first() {
+        Some(zero) if zero.area == 0 && zero.into == ZERO && zero.neighbours.is_empty() => (),
+        _ => return false,
+    }
+    let mut state = vec![UNSEEN; aggregates.len()];
+    state[0] = DONE;
+    let mut path = Vec::new();
+    for start in 1..aggregates.len() {
+        let mut i = start;
+        while state[i] != DONE {
+            if state[i] == ON_PATH {
+                // merged in a loop
+                return false;
+            }
+            state[i] = ON_PATH;
+            path.push(i);
+            let agg = &aggregates[i];
+            if agg.into.0 as usize == i {
+                if agg.area == 0 {
+                    return false;
                 }
+                break;
+            }
+            if agg.area != 0 || !agg.neighbours.is_empty() || agg.into == ZERO || agg.into.0 as usize >= aggregates.len() {
+                return false;
             }
+            i = agg.into.0 as usize;
+        }
+        for i in path.drain(..) {
+            state[i] = DONE;
         }
-
-        let mut list: Vec<AggregateIndex> = neighbours.into_iter().collect();
-        list.sort();
-        list
     }
+    aggregates.iter().enumerate().all(|(i, agg)| {
+        agg.neighbours.keys().all(|other| other.0 as usize != i && live(other))
+    })
 }
 
-impl Aggregate {
-    pub fn area(&self) -> usize {
-        self.indices.len()
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use visioncortex::BoundingRect;
+    use crate::testing::{self, persist};
+
+    fn params(order: MergeOrder) -> Params {
+        Params {
+            order,
+            ..Default::default()
+        }
+    }
+
+    fn processor(order: MergeOrder) -> Processor {
+        let mut processor = Processor::new();
+        processor.config(params(order)).unwrap();
+        processor.input(testing::clusters(testing::image(96, 64, 1))).unwrap();
+        processor
+    }
+
+    #[test]
+    fn restore_resumes_identically() {
+        for order in [MergeOrder::Index, MergeOrder::Smallest, MergeOrder::Similar] {
+            let mut uninterrupted = processor(order);
+            let mut ticks = 0;
+            while !uninterrupted.tick() {
+                ticks += 1;
+            }
+            assert!(ticks > 2, "{:?}", order);
+
+            let mut interrupted = processor(order);
+            for _ in 0..ticks / 2 {
+                interrupted.tick();
+            }
+            let progress = interrupted.progress();
+            let mut resumed = Processor::new();
+            resumed.config(params(order)).unwrap();
+            resumed.restore(persist(interrupted.snapshot().unwrap())).unwrap();
+            assert_eq!(resumed.progress(), progress, "{:?}", order);
+            while !resumed.tick() {}
+            assert_eq!(resumed.output().unwrap().pixels, uninterrupted.output().unwrap().pixels, "{:?}", order);
+        }
+    }
+
+    #[test]
+    fn restore_rejects_overflowing_size() {
+        let mut processor = Processor::new();
+        processor.input(testing::clusters(testing::image(16, 16, 2))).unwrap();
+        let mut snapshot = processor.snapshot().unwrap();
+        snapshot.width = u32::MAX;
+        snapshot.height = 2;
+        assert_eq!(Processor::new().restore(snapshot), Err(Error::InvalidSnapshot));
+    }
+
+    #[test]
+    fn restore_rejects_inconsistent_aggregates() {
+        let snapshot = || {
+            let mut processor = processor(MergeOrder::Index);
+            for _ in 0..10 {
+                processor.tick();
+            }
+            processor.snapshot().unwrap()
+        };
+        let live = |snapshot: &Snapshot| -> Vec<usize> {
+            (1..snapshot.aggregates.len()).filter(|&i| snapshot.aggregates[i].into.0 as usize == i).collect()
+        };
+        let loops = |snapshot: &mut Snapshot| {
+            let live = live(snapshot);
+            let (a, b) = (live[0], live[1]);
+            for (i, into) in [(a, b), (b, a)] {
+                let agg = &mut snapshot.aggregates[i];
+                agg.into = AggregateIndex(into as u32);
+                agg.area = 0;
+                agg.neighbours.clear();
+            }
+        };
+        let dead_end = |snapshot: &mut Snapshot| {
+            let merged = (1..snapshot.aggregates.len()).find(|&i| snapshot.aggregates[i].into.0 as usize != i).unwrap();
+            snapshot.aggregates[merged].into = ZERO;
+        };
+        let zero = |snapshot: &mut Snapshot| snapshot.aggregates[0].area = 1;
+        let neighbour = |snapshot: &mut Snapshot| {
+            let merged = (1..snapshot.aggregates.len()).find(|&i| snapshot.aggregates[i].into.0 as usize != i).unwrap();
+            let live = live(snapshot)[0];
+            snapshot.aggregates[live].neighbours.insert(AggregateIndex(merged as u32), BoundaryStrength::default());
+        };
+        let corruptions: [&dyn Fn(&mut Snapshot); 4] = [&loops, &dead_end, &zero, &neighbour];
+        for (i, corrupt) in corruptions.iter().enumerate() {
+            let mut snapshot = snapshot();
+            corrupt(&mut snapshot);
+            assert_eq!(Processor::new().restore(snapshot), Err(Error::InvalidSnapshot), "corruption {}", i);
+        }
+        Processor::new().restore(snapshot()).unwrap();
+    }
+
+    #[test]
+    fn stats_only_with_srm() {
+        let srm = || Params {
+            srm: Some(Srm::default()),
+            ..Default::default()
+        };
+        let mut processor = Processor::new();
+        processor.input(testing::clusters(testing::image(16, 16, 3))).unwrap();
+        assert!(processor.aggregates.iter().all(|agg| agg.stats.is_none()));
+        let snapshots = [processor.snapshot().unwrap(), processor.snapshot().unwrap()];
+        processor.config(srm()).unwrap();
+        assert!(processor.aggregates.iter().all(|agg| agg.stats.is_some()));
+
+        // a restored run has no pixels to measure them on
+        let mut restored = Processor::new();
+        let [snapshot, again] = snapshots;
+        restored.restore(snapshot).unwrap();
+        assert_eq!(restored.config(srm()), Err(Error::InvalidParams("srm")));
+        let mut restored = Processor::new();
+        restored.config(srm()).unwrap();
+        assert_eq!(restored.restore(again), Err(Error::InvalidParams("srm")));
+    }
+
+    #[test]
+    fn corners_border_only_with_eight() {
+        // black blocks at the top left and bottom right, white ones at the other corners
+        let mut image = ColorImage::new_w_h(16, 16);
+        for y in 0..16 {
+            for x in 0..16 {
+                let shade = if (x < 8) == (y < 8) { 0 } else { 255 };
+                image.set_pixel(x, y, &Color::new(shade, shade, shade));
+            }
+        }
+        let mut clustering = crate::Clustering::new();
+        clustering.config(crate::clustering::Params {
+            hierarchical: 16,
+            ..Default::default()
+        }).unwrap();
+        clustering.input(image).unwrap();
+        while !clustering.tick() {}
+        let clusters = clustering.output().unwrap();
+        assert_eq!(clusters.output_len(), 4);
+
+        let mut processor = Processor::new();
+        processor.input(clusters).unwrap();
+        let top_left = processor.indices[0];
+        assert_eq!(processor.get_agg(top_left).neighbours.len(), 2);
+        processor.config(Params { connectivity: Connectivity::Eight, ..Default::default() }).unwrap();
+        assert_eq!(processor.get_agg(top_left).neighbours.len(), 3);
+    }
+
+    /// indices of the aggregates not merged away
+    fn live(processor: &Processor) -> Vec<AggregateIndex> {
+        (1..processor.aggregates.len() as u32).map(AggregateIndex)
+            .filter(|aggi| processor.get_agg(*aggi).area() > 0)
+            .collect()
+    }
+
+    #[test]
+    fn smallest_leaves_none_below_min_size() {
+        let mut processor = Processor::new();
+        processor.config(Params {
+            min_size: 256,
+            policy: MergePolicy { rules: vec![MergeRule::new(Some(1.0), None)] },
+            order: MergeOrder::Smallest,
+            ..Default::default()
+        }).unwrap();
+        processor.input(testing::clusters(testing::image(96, 64, 1))).unwrap();
+        assert!(live(&processor).iter().any(|aggi| processor.get_agg(*aggi).area() < 256));
+        while !processor.tick() {}
+        let live = live(&processor);
+        assert!(live.len() > 1);
+        for aggi in live {
+            assert!(processor.get_agg(aggi).area() >= 256, "{}", aggi.0);
+        }
+    }
+
+    #[test]
+    fn similar_merges_closest_first() {
+        let mut processor = processor(MergeOrder::Similar);
+        let mut merges = 0;
+        loop {
+            let candidates: Vec<(AggregateIndex, i32)> = live(&processor).into_iter()
+                .filter_map(|aggi| processor.candidate(aggi).map(|(_, distance)| (aggi, distance)))
+                .collect();
+            if processor.tick() {
+                assert!(candidates.is_empty());
+                break;
+            }
+            let closest = candidates.iter().map(|c| c.1).min().unwrap();
+            let merged: Vec<i32> = candidates.iter()
+                .filter(|(aggi, _)| processor.get_agg(*aggi).area() == 0)
+                .map(|c| c.1)
+                .collect();
+            assert_eq!(merged, vec![closest]);
+            merges += 1;
+        }
+        assert!(merges > 2);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn mean_paints_area_weighted_mean() {
+        let mut processor = Processor::new();
+        processor.config(Params { paint: Paint::Mean, ..Default::default() }).unwrap();
+        processor.input(testing::clusters(testing::image(96, 64, 1))).unwrap();
+        let initial: Vec<Color> = processor.indices.iter().map(|aggi| processor.get_agg(*aggi).color).collect();
+        while !processor.tick() {}
+        let output = processor.output().unwrap();
+        let mut sums = vec![([0_u64; 3], 0_u64); processor.aggregates.len()];
+        for (aggi, color) in processor.pixels().iter().zip(initial.iter()) {
+            let (sum, area) = &mut sums[aggi.0 as usize];
+            for (sum, c) in sum.iter_mut().zip([color.r, color.g, color.b]) {
+                *sum += c as u64;
+            }
+            *area += 1;
+        }
+        for (px, aggi) in processor.pixels().iter().enumerate() {
+            let (sum, area) = sums[aggi.0 as usize];
+            let color = output.get_pixel(px % 96, px / 96);
+            assert_eq!([color.r, color.g, color.b], sum.map(|sum| (sum / area) as u8), "{}", px);
+        }
+        processor.config(Params::default()).unwrap();
+        while !processor.tick() {}
+        assert!(processor.output().unwrap().pixels != output.pixels);
+    }
+
+    #[test]
+    fn config_after_input_starts_over() {
+        let changed = |order| Params {
+            deviation: 0.5,
+            min_size: 256,
+            order,
+            ..Default::default()
+        };
+        for order in [MergeOrder::Index, MergeOrder::Smallest, MergeOrder::Similar] {
+            let mut fresh = Processor::new();
+            fresh.config(changed(order)).unwrap();
+            fresh.input(testing::clusters(testing::image(96, 64, 1))).unwrap();
+            while !fresh.tick() {}
+            let expected = fresh.output().unwrap().pixels;
+
+            // reconfigured both midway and after finishing
+            for ticks in [5, usize::MAX] {
+                let mut processor = processor(MergeOrder::Smallest);
+                for _ in 0..ticks {
+                    if processor.tick() {
+                        break;
+                    }
+                }
+                processor.config(changed(order)).unwrap();
+                assert_eq!(processor.progress(), 0, "{:?}", order);
+                while !processor.tick() {}
+                assert_eq!(processor.output().unwrap().pixels, expected, "{:?} {}", order, ticks);
+            }
+        }
+    }
+
+    #[test]
+    fn progress_is_monotone_until_finished() {
+        for order in [MergeOrder::Index, MergeOrder::Smallest, MergeOrder::Similar] {
+            let mut processor = processor(order);
+            let mut last = processor.progress();
+            assert!(last < 100, "{:?}", order);
+            loop {
+                let finished = processor.tick();
+                let progress = processor.progress();
+                assert!(progress >= last, "{:?}", order);
+                assert_eq!(progress == 100, finished, "{:?}", order);
+                if finished {
+                    break;
+                }
+                last = progress;
+            }
+        }
+    }
+
+    #[test]
+    fn strong_edges_refuse_merges() {
+        let image = || testing::blocks(&[&[[200, 0, 0], [190, 0, 0]]]);
+        let params = || Params { min_size: 256, ..Default::default() };
+        // a sharp edge between the blocks, unlike in the input
+        let source = || Some(testing::blocks(&[&[[0, 0, 0], [255, 255, 255]]]));
+        let edge = Some(EdgeParams { weight: 0.0, threshold: 0.5 });
+        let regions = |params: Params| {
+            let mut processor = Processor::new();
+            processor.config(params).unwrap();
+            processor.input(testing::block_clusters(image())).unwrap();
+            while !processor.tick() {}
+            live(&processor).len()
+        };
+        assert_eq!(regions(params()), 1);
+        assert_eq!(regions(Params { edge, ..params() }), 1);
+        assert_eq!(regions(Params { edge, source: source(), ..params() }), 2);
+        // unless the patches are small enough to merge regardless of color
+        assert_eq!(regions(Params { edge, source: source(), min_size: 4096, ..params() }), 1);
+    }
+
+    #[test]
+    fn output_regions_of_blocks() {
+        // the red blocks touch only at a corner
+        let colors: &[&[[u8; 3]]] = &[&[[200, 0, 0], [0, 0, 200]], &[[0, 200, 0], [200, 0, 0]]];
+        let mut processor = Processor::new();
+        // too large to merge regardless of color, and with no color difference allowed
+        processor.config(Params { deviation: 0.0, min_size: 64, ..Default::default() }).unwrap();
+        processor.input(testing::block_clusters(testing::blocks(colors))).unwrap();
+        while !processor.tick() {}
+        testing::assert_block_regions(&processor.output_regions().unwrap(), colors);
+
+        // the mean over the area, whatever the paint
+        let mut processor = Processor::new();
+        processor.input(testing::block_clusters(testing::blocks(&[&[[200, 0, 0], [190, 0, 0]]]))).unwrap();
+        while !processor.tick() {}
+        let map = processor.output_regions().unwrap();
+        assert!(map.labels.pixels.iter().all(|label| *label == 0));
+        assert_eq!(map.regions.len(), 1);
+        let region = &map.regions[0];
+        assert_eq!((region.area, region.rect), (128, BoundingRect::new_x_y_w_h(0, 0, 16, 8)));
+        assert_eq!((region.color.r, region.color.g, region.color.b), (195, 0, 0));
+    }
+}
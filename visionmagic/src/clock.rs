@@ -0,0 +1,63 @@
+//! Clock abstraction for time-budgeted ticking
+//!
+//! `std::time::Instant` is unavailable on `wasm32-unknown-unknown`, so [`SystemClock`]
+//! falls back to `Date.now()` there. Callers with their own time source (e.g. a frame
+//! timestamp) can implement [`Clock`] themselves.
+use std::time::Duration;
+
+/// A monotonic source of time
+pub trait Clock {
+    /// Time elapsed since an arbitrary but fixed origin
+    fn now(&self) -> Duration;
+}
+
+/// The wall clock of the host platform
+pub struct SystemClock {
+    #[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+    origin: std::time::Instant,
+}
+
+impl SystemClock {
+    pub fn new() -> Self {
+        Self {
+            #[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+            origin: std::time::Instant::now(),
+        }
+    }
+}
+
+impl Default for SystemClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for SystemClock {
+    #[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+    fn now(&self) -> Duration {
+        self.origin.elapsed()
+    }
+
+    #[cfg(all(target_arch = "wasm32", target_os = "unknown"))]
+    fn now(&self) -> Duration {
+        Duration::from_secs_f64(js_sys::Date::now() / 1000.0)
+    }
+}
+
+/// Call `tick` repeatedly until it returns true or `budget` is used up;
+/// returns true when finished. `tick` is called at least once.
+pub fn run_for<C, F>(budget: Duration, clock: &C, mut tick: F) -> bool
+where
+    C: Clock,
+    F: FnMut() -> bool,
+{
+    let start = clock.now();
+    loop {
+        if tick() {
+            return true;
+        }
+        if clock.now().saturating_sub(start) >= budget {
+            return false;
+        }
+    }
+}
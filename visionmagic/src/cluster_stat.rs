@@ -1,6 +1,8 @@
 //! Processor to calculate color statistic on set of clusters
 use visioncortex::{ColorStat, ColorStatBuilder};
 use visioncortex::color_clusters::Clusters;
+use crate::Error;
+use crate::parallel;
 use crate::pipeline::Processor as ProcessorTrait;
 
 #[derive(Default)]
@@ -30,47 +32,68 @@ impl ProcessorTrait for Processor {
         Self::default()
     }
 
-    fn config(&mut self, params: Params) -> bool {
+    fn config(&mut self, params: Params) -> Result<(), Error> {
         self.params = params;
-        true
+        Ok(())
     }
 
-    fn input(&mut self, input: Input) -> bool {
+    fn input(&mut self, input: Input) -> Result<(), Error> {
+        let len = input.output_len();
+        if len == 0 {
+            return Err(Error::EmptyInput);
+        }
         self.clusters = Some(input);
         self.builder = ColorStatBuilder::new();
-        let len = self.clusters.as_ref().unwrap().output_len();
-        self.counter = if len > 0 { len - 1 } else { 0 };
-        len > 0
+        self.counter = len - 1;
+        Ok(())
     }
 
     fn tick(&mut self) -> bool {
-        let view = self.clusters.as_ref().unwrap().view();
-        let index = view.clusters_output[self.counter];
-        let cluster = view.get_cluster(index);
-        self.builder.add(cluster.residue_color());
-        if self.counter > 0 {
-            self.counter -= 1;
+        let view = match self.clusters.as_ref() {
+            Some(clusters) => clusters.view(),
+            None => return true,
+        };
+        let start = self.counter.saturating_sub(parallel::batch() - 1);
+        let batch = &view.clusters_output[start..=self.counter];
+        let colors = parallel::map(batch, |index| view.get_cluster(*index).residue_color());
+        for color in colors.into_iter().rev() {
+            self.builder.add(color);
+        }
+        if start > 0 {
+            self.counter = start - 1;
             false
         } else {
+            self.counter = 0;
             true
         }
     }
 
     fn progress(&self) -> u32 {
-        let total = self.clusters.as_ref().unwrap().output_len() - 1;
-        100 - 100 * self.counter as u32 / total as u32
+        let total = match self.clusters.as_ref() {
+            Some(clusters) => clusters.output_len() - 1,
+            None => return 0,
+        };
+        if total == 0 {
+            100
+        } else {
+            100 - 100 * self.counter as u32 / total as u32
+        }
     }
 
-    fn output(&mut self) -> Output {
+    fn output(&mut self) -> Result<Output, Error> {
+        if self.clusters.is_none() {
+            return Err(Error::WrongStage("no input"));
+        }
         let output = self.builder.build();
         log::info!("mean = {:?}, deviation = {:?}", output.mean, output.deviation);
-        output
+        Ok(output)
     }
 
 }
 
 impl Processor {
-    pub fn take(&mut self) -> Clusters {
-        self.clusters.take().unwrap()
+    /// take back the input clusters
+    pub fn take(&mut self) -> Result<Clusters, Error> {
+        self.clusters.take().ok_or(Error::OutputTaken)
     }
 }
\ No newline at end of file
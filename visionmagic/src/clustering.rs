@@ -1,12 +1,14 @@
 //! Processor to perform clustering & hierarchical clustering on an image
 use visioncortex::ColorImage;
 use visioncortex::color_clusters::{Clusters, IncrementalBuilder, Runner, RunnerConfig, HIERARCHICAL_MAX};
+use crate::Error;
 use crate::pipeline::Processor as ProcessorTrait;
 
 #[derive(Default)]
 pub struct Processor {
     params: Params,
     builder: Option<IncrementalBuilder>,
+    taken: bool,
 }
 
 /// [`ColorImage`]
@@ -20,6 +22,32 @@ pub struct Params {
     pub color_levels: u32,
     /// Perform hierarchical clustering up to this size (area)
     pub hierarchical: u32,
+    /// Which pixels of the same color form a cluster together
+    pub connectivity: Connectivity,
+    /// Valid range is 1~2147483647. Pixels grouped per tick at first; only affects how finely
+    /// the work is divided
+    pub batch_size: u32,
+    /// Clusters of this area or smaller never become output clusters but merge into a
+    /// neighbour; unless this is 0, neither do those whose perimeter is not less than their area
+    pub good_min_area: usize,
+    /// Valid range is 0~7. Bits dropped from each channel before comparing colors at first;
+    /// more gives larger initial clusters
+    pub is_same_color_a: i32,
+    /// Valid range is 0~255. Largest difference in any channel, after dropping bits, of colors
+    /// deemed the same at first; raise to absorb noise, e.g. of scans
+    pub is_same_color_b: i32,
+    /// Clusters with this many neighbours or fewer leave a hole in the cluster they merge into
+    pub hollow_neighbours: usize,
+}
+
+/// Which pixels count as adjacent
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Connectivity {
+    /// Those sharing an edge
+    #[default]
+    Four,
+    /// Those sharing an edge or a corner, which keeps thin diagonal strokes together
+    Eight,
 }
 
 impl Default for Params {
@@ -27,12 +55,34 @@ impl Default for Params {
         Self {
             color_levels: Self::MAX_COLOR_LEVELS,
             hierarchical: HIERARCHICAL_MAX,
+            connectivity: Connectivity::default(),
+            batch_size: 25600,
+            good_min_area: 1,
+            is_same_color_a: 0,
+            is_same_color_b: 1,
+            hollow_neighbours: 0,
         }
     }
 }
 
 impl Params {
     pub const MAX_COLOR_LEVELS: u32 = 256;
+
+    fn validate(&self) -> Result<(), Error> {
+        if self.color_levels < 1 || self.color_levels > Self::MAX_COLOR_LEVELS {
+            return Err(Error::InvalidParams("color_levels"));
+        }
+        if self.batch_size < 1 || self.batch_size > i32::MAX as u32 {
+            return Err(Error::InvalidParams("batch_size"));
+        }
+        if !(0..8).contains(&self.is_same_color_a) {
+            return Err(Error::InvalidParams("is_same_color_a"));
+        }
+        if !(0..=255).contains(&self.is_same_color_b) {
+            return Err(Error::InvalidParams("is_same_color_b"));
+        }
+        Ok(())
+    }
 }
 
 impl ProcessorTrait for Processor {
@@ -46,43 +96,133 @@ impl ProcessorTrait for Processor {
     }
 
     /// configure clustering parameters; can be reconfigured on runtime
-    fn config(&mut self, params: Params) -> bool {
+    fn config(&mut self, params: Params) -> Result<(), Error> {
+        params.validate()?;
         self.params = params;
         if self.builder.is_some() {
-            let clusters = self.output();
-            self.input(clusters.take_image());
+            let clusters = self.output()?;
+            self.input(clusters.take_image())?;
         }
-        true
+        Ok(())
     }
 
-    fn input(&mut self, input: Input) -> bool {
-        let non_empty = input.width > 0 && input.height > 0;
+    fn input(&mut self, input: Input) -> Result<(), Error> {
+        if input.width == 0 || input.height == 0 {
+            return Err(Error::EmptyInput);
+        }
         let runner = Runner::new(RunnerConfig {
-            diagonal: false,
+            diagonal: self.params.connectivity == Connectivity::Eight,
             hierarchical: self.params.hierarchical,
-            batch_size: 25600,
-            good_min_area: 1,
-            good_max_area: (input.width * input.height) as usize,
-            is_same_color_a: 0,
-            is_same_color_b: 1,
+            batch_size: self.params.batch_size as i32,
+            good_min_area: self.params.good_min_area,
+            good_max_area: input.width * input.height,
+            is_same_color_a: self.params.is_same_color_a,
+            is_same_color_b: self.params.is_same_color_b,
             deepen_diff: (Params::MAX_COLOR_LEVELS / self.params.color_levels) as i32,
-            hollow_neighbours: 0,
+            hollow_neighbours: self.params.hollow_neighbours,
         }, input);
         self.builder = Some(runner.start());
-        non_empty
+        self.taken = false;
+        Ok(())
     }
 
     fn tick(&mut self) -> bool {
-        self.builder.as_mut().unwrap().tick()
+        match self.builder.as_mut() {
+            Some(builder) => builder.tick(),
+            None => true,
+        }
     }
 
     fn progress(&self) -> u32 {
-        self.builder.as_ref().unwrap().progress()
+        match self.builder.as_ref() {
+            Some(builder) => builder.progress(),
+            None => if self.taken { 100 } else { 0 },
+        }
     }
 
     /// to be called once only after process ends
-    fn output(&mut self) -> Output {
-        self.builder.take().unwrap().result()
+    fn output(&mut self) -> Result<Output, Error> {
+        match self.builder.take() {
+            Some(mut builder) => {
+                self.taken = true;
+                Ok(builder.result())
+            },
+            None if self.taken => Err(Error::OutputTaken),
+            None => Err(Error::WrongStage("no input")),
+        }
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use visioncortex::Color;
+    use crate::testing;
+
+    const WHITE: [u8; 3] = [255, 255, 255];
+    const BLACK: [u8; 3] = [0, 0, 0];
+
+    /// a black block in the middle of white ones
+    fn speck() -> ColorImage {
+        testing::blocks(&[&[WHITE, WHITE, WHITE], &[WHITE, BLACK, WHITE], &[WHITE, WHITE, WHITE]])
+    }
+
+    /// two blocks of grays 3 apart
+    fn grays() -> ColorImage {
+        testing::blocks(&[&[[100, 100, 100], [103, 103, 103]]])
     }
 
+    /// area, color and number of hole pixels of each output cluster, with the number of ticks taken
+    fn run(params: Params, image: ColorImage) -> (Vec<(usize, Color, usize)>, usize) {
+        let mut processor = Processor::new();
+        processor.config(params).unwrap();
+        processor.input(image).unwrap();
+        let mut ticks = 1;
+        while !processor.tick() {
+            ticks += 1;
+        }
+        let clusters = processor.output().unwrap();
+        let view = clusters.view();
+        let outputs = view.clusters_output.iter().map(|index| {
+            let cluster = view.get_cluster(*index);
+            (cluster.area(), cluster.color(), cluster.holes.len())
+        }).collect();
+        (outputs, ticks)
+    }
+
+    #[test]
+    fn batch_size_only_divides_work() {
+        let image = || testing::image(64, 64, 1);
+        let (outputs, ticks) = run(Params::default(), image());
+        let (batched, more) = run(Params { batch_size: 64, ..Default::default() }, image());
+        assert_eq!(batched, outputs);
+        assert!(more > ticks);
+    }
+
+    #[test]
+    fn good_min_area_drops_small_clusters() {
+        let (outputs, _) = run(Params::default(), speck());
+        assert_eq!(outputs.iter().map(|o| o.0).collect::<Vec<_>>(), [64, 576]);
+        let (outputs, _) = run(Params { good_min_area: 64, ..Default::default() }, speck());
+        assert_eq!(outputs.iter().map(|o| o.0).collect::<Vec<_>>(), [576]);
+    }
+
+    #[test]
+    fn is_same_color_joins_close_colors() {
+        let areas = |params| run(params, grays()).0.iter().map(|o| o.0).collect::<Vec<_>>();
+        assert_eq!(areas(Params::default()), [64, 128]);
+        // 100 and 103 are both 25 without their two lowest bits
+        assert_eq!(areas(Params { is_same_color_a: 2, ..Default::default() }), [128]);
+        assert_eq!(areas(Params { is_same_color_b: 3, ..Default::default() }), [128]);
+        assert_eq!(areas(Params { is_same_color_b: 2, ..Default::default() }), [64, 128]);
+    }
+
+    #[test]
+    fn hollow_neighbours_leaves_holes() {
+        let holes = |params| run(params, speck()).0.iter().map(|o| o.2).collect::<Vec<_>>();
+        assert_eq!(holes(Params::default()), [0, 0]);
+        // the black block has only the white around it as neighbour
+        assert_eq!(holes(Params { hollow_neighbours: 1, ..Default::default() }), [0, 64]);
+    }
 }
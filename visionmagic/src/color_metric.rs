@@ -0,0 +1,242 @@
+//! Color distance metrics used to decide whether two shapes should merge
+//!
+//! A `deviation` parameter is measured in the unit of the metric in use. The built-in
+//! metrics are scaled such that 1.0 is a large difference, e.g. the CIELAB metrics report
+//! ΔE / 100, where a ΔE of 100 separates black from white.
+use visioncortex::Color;
+
+/// Distance between two colors; must be symmetric and zero for identical colors
+pub trait ColorMetric: Send + Sync {
+    fn distance(&self, a: Color, b: Color) -> f64;
+}
+
+/// Weighted sum of differences in hue, saturation and value
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Hsv {
+    pub hue: f64,
+    pub saturation: f64,
+    pub value: f64,
+}
+
+/// CIE 1976 color difference (ΔE*ab), i.e. Euclidean distance in CIELAB
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Cie76;
+
+/// CIEDE2000 color difference (ΔE00), which corrects CIE76 for perceptual uniformity
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Ciede2000;
+
+/// Weighted Euclidean distance in sRGB, with each channel scaled to 0~1
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct WeightedRgb {
+    pub r: f64,
+    pub g: f64,
+    pub b: f64,
+}
+
+impl Hsv {
+    pub fn new(hue: f64, saturation: f64, value: f64) -> Self {
+        Self { hue, saturation, value }
+    }
+}
+
+impl ColorMetric for Hsv {
+    fn distance(&self, a: Color, b: Color) -> f64 {
+        let a = a.to_hsv();
+        let b = b.to_hsv();
+        return self.hue * wrap(a.h, b.h) + self.saturation * (a.s - b.s).abs() + self.value * (a.v - b.v).abs();
+
+        fn wrap(x: f64, y: f64) -> f64 {
+            let d = (x - y).abs();
+            if d < 0.5 {
+                d
+            } else {
+                1.0 - d
+            }
+        }
+    }
+}
+
+impl ColorMetric for Cie76 {
+    fn distance(&self, a: Color, b: Color) -> f64 {
+        let a = Lab::from(a);
+        let b = Lab::from(b);
+        ((a.l - b.l).powi(2) + (a.a - b.a).powi(2) + (a.b - b.b).powi(2)).sqrt() / 100.0
+    }
+}
+
+impl ColorMetric for Ciede2000 {
+    fn distance(&self, a: Color, b: Color) -> f64 {
+        ciede2000(Lab::from(a), Lab::from(b)) / 100.0
+    }
+}
+
+impl WeightedRgb {
+    pub fn new(r: f64, g: f64, b: f64) -> Self {
+        Self { r, g, b }
+    }
+}
+
+impl Default for WeightedRgb {
+    /// weights loosely following the eye's sensitivity to each primary
+    fn default() -> Self {
+        Self::new(2.0 / 9.0, 4.0 / 9.0, 3.0 / 9.0)
+    }
+}
+
+impl ColorMetric for WeightedRgb {
+    fn distance(&self, a: Color, b: Color) -> f64 {
+        let d = |x: u8, y: u8| (x as f64 - y as f64) / 255.0;
+        (self.r * d(a.r, b.r).powi(2) + self.g * d(a.g, b.g).powi(2) + self.b * d(a.b, b.b).powi(2)).sqrt()
+    }
+}
+
+/// CIELAB under the D65 white point
+#[derive(Clone, Copy, Debug)]
+struct Lab {
+    l: f64,
+    a: f64,
+    b: f64,
+}
+
+impl From<Color> for Lab {
+    fn from(color: Color) -> Self {
+        let linear = |c: u8| {
+            let c = c as f64 / 255.0;
+            if c <= 0.04045 { c / 12.92 } else { ((c + 0.055) / 1.055).powf(2.4) }
+        };
+        let (r, g, b) = (linear(color.r), linear(color.g), linear(color.b));
+        let x = (0.4124564 * r + 0.3575761 * g + 0.1804375 * b) / 0.95047;
+        let y = 0.2126729 * r + 0.7151522 * g + 0.0721750 * b;
+        let z = (0.0193339 * r + 0.1191920 * g + 0.9503041 * b) / 1.08883;
+        let f = |t: f64| {
+            const DELTA: f64 = 6.0 / 29.0;
+            if t > DELTA * DELTA * DELTA { t.cbrt() } else { t / (3.0 * DELTA * DELTA) + 4.0 / 29.0 }
+        };
+        let (fx, fy, fz) = (f(x), f(y), f(z));
+        Self {
+            l: 116.0 * fy - 16.0,
+            a: 500.0 * (fx - fy),
+            b: 200.0 * (fy - fz),
+        }
+    }
+}
+
+/// after G. Sharma, W. Wu and E. N. Dalal, "The CIEDE2000 Color-Difference Formula:
+/// Implementation Notes, Supplementary Test Data, and Mathematical Observations" (2005)
+fn ciede2000(x: Lab, y: Lab) -> f64 {
+    let pow7 = |v: f64| v.powi(7);
+    let c_bar = (x.a.hypot(x.b) + y.a.hypot(y.b)) / 2.0;
+    let g = 0.5 * (1.0 - (pow7(c_bar) / (pow7(c_bar) + pow7(25.0))).sqrt());
+    let (a1, a2) = ((1.0 + g) * x.a, (1.0 + g) * y.a);
+    let (c1, c2) = (a1.hypot(x.b), a2.hypot(y.b));
+    let hue = |b: f64, a: f64| {
+        if a == 0.0 && b == 0.0 { 0.0 } else { b.atan2(a).to_degrees().rem_euclid(360.0) }
+    };
+    let (h1, h2) = (hue(x.b, a1), hue(y.b, a2));
+
+    let delta_l = y.l - x.l;
+    let delta_c = c2 - c1;
+    let delta_h = if c1 * c2 == 0.0 {
+        0.0
+    } else if (h2 - h1).abs() <= 180.0 {
+        h2 - h1
+    } else if h2 - h1 > 180.0 {
+        h2 - h1 - 360.0
+    } else {
+        h2 - h1 + 360.0
+    };
+    let delta_h = 2.0 * (c1 * c2).sqrt() * (delta_h / 2.0).to_radians().sin();
+
+    let l_bar = (x.l + y.l) / 2.0;
+    let c_bar = (c1 + c2) / 2.0;
+    let h_bar = if c1 * c2 == 0.0 {
+        h1 + h2
+    } else if (h1 - h2).abs() <= 180.0 {
+        (h1 + h2) / 2.0
+    } else if h1 + h2 < 360.0 {
+        (h1 + h2 + 360.0) / 2.0
+    } else {
+        (h1 + h2 - 360.0) / 2.0
+    };
+    let cos = |degrees: f64| degrees.to_radians().cos();
+    let t = 1.0 - 0.17 * cos(h_bar - 30.0) + 0.24 * cos(2.0 * h_bar)
+        + 0.32 * cos(3.0 * h_bar + 6.0) - 0.20 * cos(4.0 * h_bar - 63.0);
+    let delta_theta = 30.0 * (-((h_bar - 275.0) / 25.0).powi(2)).exp();
+    let r_c = 2.0 * (pow7(c_bar) / (pow7(c_bar) + pow7(25.0))).sqrt();
+    let s_l = 1.0 + 0.015 * (l_bar - 50.0).powi(2) / (20.0 + (l_bar - 50.0).powi(2)).sqrt();
+    let s_c = 1.0 + 0.045 * c_bar;
+    let s_h = 1.0 + 0.015 * c_bar * t;
+    let r_t = -(2.0 * delta_theta).to_radians().sin() * r_c;
+
+    let (l, c, h) = (delta_l / s_l, delta_c / s_c, delta_h / s_h);
+    (l * l + c * c + h * h + r_t * c * h).sqrt()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// (L*a*b* of the first color, of the second, ΔE00) from the supplementary test data of Sharma
+    /// et al., which exercises the hue wrap-around and mean-hue corner cases of the formula
+    const SHARMA: [([f64; 3], [f64; 3], f64); 34] = [
+        ([50.0, 2.6772, -79.7751], [50.0, 0.0, -82.7485], 2.0425),
+        ([50.0, 3.1571, -77.2803], [50.0, 0.0, -82.7485], 2.8615),
+        ([50.0, 2.8361, -74.0200], [50.0, 0.0, -82.7485], 3.4412),
+        ([50.0, -1.3802, -84.2814], [50.0, 0.0, -82.7485], 1.0000),
+        ([50.0, -1.1848, -84.8006], [50.0, 0.0, -82.7485], 1.0000),
+        ([50.0, -0.9009, -85.5211], [50.0, 0.0, -82.7485], 1.0000),
+        ([50.0, 0.0, 0.0], [50.0, -1.0, 2.0], 2.3669),
+        ([50.0, -1.0, 2.0], [50.0, 0.0, 0.0], 2.3669),
+        ([50.0, 2.4900, -0.0010], [50.0, -2.4900, 0.0009], 7.1792),
+        ([50.0, 2.4900, -0.0010], [50.0, -2.4900, 0.0010], 7.1792),
+        ([50.0, 2.4900, -0.0010], [50.0, -2.4900, 0.0011], 7.2195),
+        ([50.0, 2.4900, -0.0010], [50.0, -2.4900, 0.0012], 7.2195),
+        ([50.0, -0.0010, 2.4900], [50.0, 0.0009, -2.4900], 4.8045),
+        ([50.0, -0.0010, 2.4900], [50.0, 0.0010, -2.4900], 4.8045),
+        ([50.0, -0.0010, 2.4900], [50.0, 0.0011, -2.4900], 4.7461),
+        ([50.0, 2.5, 0.0], [50.0, 0.0, -2.5], 4.3065),
+        ([50.0, 2.5, 0.0], [73.0, 25.0, -18.0], 27.1492),
+        ([50.0, 2.5, 0.0], [61.0, -5.0, 29.0], 22.8977),
+        ([50.0, 2.5, 0.0], [56.0, -27.0, -3.0], 31.9030),
+        ([50.0, 2.5, 0.0], [58.0, 24.0, 15.0], 19.4535),
+        ([50.0, 2.5, 0.0], [50.0, 3.1736, 0.5854], 1.0000),
+        ([50.0, 2.5, 0.0], [50.0, 3.2972, 0.0], 1.0000),
+        ([50.0, 2.5, 0.0], [50.0, 1.8634, 0.5757], 1.0000),
+        ([50.0, 2.5, 0.0], [50.0, 3.2592, 0.3350], 1.0000),
+        ([60.2574, -34.0099, 36.2677], [60.4626, -34.1751, 39.4387], 1.2644),
+        ([63.0109, -31.0961, -5.8663], [62.8187, -29.7946, -4.0864], 1.2630),
+        ([61.2901, 3.7196, -5.3901], [61.4292, 2.2480, -4.9620], 1.8731),
+        ([35.0831, -44.1164, 3.7933], [35.0232, -40.0716, 1.5901], 1.8645),
+        ([22.7233, 20.0904, -46.6940], [23.0331, 14.9730, -42.5619], 2.0373),
+        ([36.4612, 47.8580, 18.3852], [36.2715, 50.5065, 21.2231], 1.4146),
+        ([90.8027, -2.0831, 1.4410], [91.1528, -1.6435, 0.0447], 1.4441),
+        ([90.9257, -0.5406, -0.9208], [88.6381, -0.8985, -0.7239], 1.5381),
+        ([6.7747, -0.2908, -2.4247], [5.8714, -0.0985, -2.2286], 0.6377),
+        ([2.0776, 0.0795, -1.1350], [0.9033, -0.0636, -0.5514], 0.9082),
+    ];
+
+    fn lab([l, a, b]: [f64; 3]) -> Lab {
+        Lab { l, a, b }
+    }
+
+    #[test]
+    fn ciede2000_matches_reference_data() {
+        for (i, &(x, y, expected)) in SHARMA.iter().enumerate() {
+            // the published values are rounded to 4 decimal places
+            let forward = ciede2000(lab(x), lab(y));
+            let backward = ciede2000(lab(y), lab(x));
+            assert!((forward - expected).abs() < 5e-5, "pair {}: {} != {}", i + 1, forward, expected);
+            assert!((backward - expected).abs() < 5e-5, "pair {} reversed: {} != {}", i + 1, backward, expected);
+        }
+    }
+
+    #[test]
+    fn lab_of_srgb_extremes() {
+        let white = Lab::from(Color::new(255, 255, 255));
+        let black = Lab::from(Color::new(0, 0, 0));
+        assert!((white.l - 100.0).abs() < 1e-3 && white.a.abs() < 1e-3 && white.b.abs() < 1e-3, "{:?}", white);
+        assert!(black.l.abs() < 1e-9 && black.a.abs() < 1e-9 && black.b.abs() < 1e-9, "{:?}", black);
+        assert!((Ciede2000.distance(Color::new(0, 0, 0), Color::new(255, 255, 255)) - 1.0).abs() < 1e-3);
+    }
+}
@@ -0,0 +1,302 @@
+//! Fitting cubic Bezier curves to polylines, after Philip J. Schneider's
+//! "An Algorithm for Automatically Fitting Digitized Curves" (Graphics Gems, 1990).
+//!
+//! Unlike `Spline::from_path_f64`, which places one curve between every pair of
+//! inflection points, a curve here spans as many points as the error bound allows.
+//! On smoothed cluster outlines the former cuts at nearly every vertex: simplifying
+//! `luca-bravo-zAjdgNXsMeg-unsplash-s.jpg` at half fidelity and shape details, its
+//! SVG is 2.05 MB against 2.08 MB of polygons, while fitting here gives 1.26 MB.
+use visioncortex::{PathF64, PointF64, Spline};
+
+/// Newton-Raphson rounds to try before splitting a curve
+const MAX_REPARAMETERIZE: usize = 4;
+
+/// Fit a closed polyline (whose last point repeats the first) with a spline that
+/// deviates from it by at most `tolerance`. Vertices turning by more than
+/// `corner_threshold` radians are kept as sharp corners.
+pub(crate) fn fit_closed(path: &PathF64, corner_threshold: f64, tolerance: f64) -> Spline {
+    let mut points: Vec<PointF64> = Vec::with_capacity(path.len());
+    for &point in path.iter() {
+        if points.last() != Some(&point) {
+            points.push(point);
+        }
+    }
+    if points.len() > 1 && points.first() == points.last() {
+        points.pop();
+    }
+    let len = points.len();
+    if len < 3 {
+        let mut spline = Spline::new(points.first().copied().unwrap_or_default());
+        for &point in points.iter().skip(1).chain(points.first()) {
+            spline.add(point, point, point);
+        }
+        return spline;
+    }
+
+    let corners: Vec<usize> = (0..len)
+        .filter(|&i| turn(points[(i + len - 1) % len], points[i], points[(i + 1) % len]) > corner_threshold)
+        .collect();
+    let start = corners.first().copied().unwrap_or(0);
+    points.rotate_left(start);
+    points.push(points[0]);
+
+    let mut spline = Spline::new(points[0]);
+    if corners.is_empty() {
+        // a smooth loop; the seam takes its tangent from both neighbours
+        let tangent = normalize(points[1] - points[len - 1]);
+        fit_cubic(&points, tangent, -tangent, tolerance, &mut spline);
+    } else {
+        let mut cuts: Vec<usize> = corners.iter().map(|corner| corner - start).collect();
+        cuts.push(len);
+        for cut in cuts.windows(2) {
+            let run = &points[cut[0]..=cut[1]];
+            let n = run.len();
+            fit_cubic(run, normalize(run[1] - run[0]), normalize(run[n - 2] - run[n - 1]), tolerance, &mut spline);
+        }
+    }
+    spline
+}
+
+/// Append curves approximating `points` to `spline`, which must end at `points[0]`.
+/// `left` and `right` are the unit tangents at both ends, pointing into the curve.
+fn fit_cubic(points: &[PointF64], left: PointF64, right: PointF64, tolerance: f64, spline: &mut Spline) {
+    let n = points.len();
+    let (first, last) = (points[0], points[n - 1]);
+    if n == 2 {
+        let alpha = distance(first, last) / 3.0;
+        spline.add(first + scale(left, alpha), last + scale(right, alpha), last);
+        return;
+    }
+
+    let max_error = tolerance * tolerance;
+    let mut u = chord_length_parameterize(points);
+    let mut bezier = generate_bezier(points, &u, left, right);
+    let (error, mut split) = compute_max_error(points, &bezier, &u);
+    if error < max_error {
+        spline.add(bezier[1], bezier[2], bezier[3]);
+        return;
+    }
+    // close enough that reparameterization may do the job
+    if error < max_error * 4.0 {
+        for _ in 0..MAX_REPARAMETERIZE {
+            u = reparameterize(points, &u, &bezier);
+            bezier = generate_bezier(points, &u, left, right);
+            let (new_error, new_split) = compute_max_error(points, &bezier, &u);
+            if new_error < max_error {
+                spline.add(bezier[1], bezier[2], bezier[3]);
+                return;
+            }
+            split = new_split;
+        }
+    }
+
+    let mut center = normalize(points[split - 1] - points[split + 1]);
+    if center == PointF64::default() {
+        center = normalize(points[split - 1] - points[split]);
+    }
+    fit_cubic(&points[..=split], left, center, tolerance, spline);
+    fit_cubic(&points[split..], -center, right, tolerance, spline);
+}
+
+/// Least-squares control points with the given end tangents
+fn generate_bezier(points: &[PointF64], u: &[f64], left: PointF64, right: PointF64) -> [PointF64; 4] {
+    let n = points.len();
+    let (first, last) = (points[0], points[n - 1]);
+    let (mut c00, mut c01, mut c11) = (0.0, 0.0, 0.0);
+    let (mut x0, mut x1) = (0.0, 0.0);
+    for (point, &t) in points.iter().zip(u.iter()) {
+        let [b0, b1, b2, b3] = bernstein(t);
+        let a1 = scale(left, b1);
+        let a2 = scale(right, b2);
+        c00 += a1.dot(a1);
+        c01 += a1.dot(a2);
+        c11 += a2.dot(a2);
+        let rest = *point - (scale(first, b0 + b1) + scale(last, b2 + b3));
+        x0 += a1.dot(rest);
+        x1 += a2.dot(rest);
+    }
+    let det = c00 * c11 - c01 * c01;
+    let (mut alpha_l, mut alpha_r) = if det != 0.0 {
+        ((x0 * c11 - x1 * c01) / det, (c00 * x1 - c01 * x0) / det)
+    } else {
+        (0.0, 0.0)
+    };
+    // fall back to the Wu/Barsky heuristic if the solution degenerates
+    let segment_length = distance(first, last);
+    let epsilon = 1e-6 * segment_length;
+    if alpha_l < epsilon || alpha_r < epsilon {
+        alpha_l = segment_length / 3.0;
+        alpha_r = alpha_l;
+    }
+    [first, first + scale(left, alpha_l), last + scale(right, alpha_r), last]
+}
+
+/// Largest squared distance between the points and the curve, and where it happens
+fn compute_max_error(points: &[PointF64], bezier: &[PointF64; 4], u: &[f64]) -> (f64, usize) {
+    let n = points.len();
+    let mut max = (0.0, n / 2);
+    for i in 1..n - 1 {
+        let diff = evaluate(bezier, u[i]) - points[i];
+        let error = diff.dot(diff);
+        if error >= max.0 {
+            max = (error, i);
+        }
+    }
+    max
+}
+
+/// One Newton-Raphson step towards the closest point on the curve, for every point
+fn reparameterize(points: &[PointF64], u: &[f64], bezier: &[PointF64; 4]) -> Vec<f64> {
+    let d1 = [
+        scale(bezier[1] - bezier[0], 3.0),
+        scale(bezier[2] - bezier[1], 3.0),
+        scale(bezier[3] - bezier[2], 3.0),
+    ];
+    let d2 = [scale(d1[1] - d1[0], 2.0), scale(d1[2] - d1[1], 2.0)];
+    points.iter().zip(u.iter()).map(|(point, &t)| {
+        let diff = evaluate(bezier, t) - *point;
+        let s = 1.0 - t;
+        let q1 = scale(d1[0], s * s) + scale(d1[1], 2.0 * s * t) + scale(d1[2], t * t);
+        let q2 = scale(d2[0], s) + scale(d2[1], t);
+        let denominator = q1.dot(q1) + diff.dot(q2);
+        if denominator == 0.0 {
+            t
+        } else {
+            t - diff.dot(q1) / denominator
+        }
+    }).collect()
+}
+
+fn chord_length_parameterize(points: &[PointF64]) -> Vec<f64> {
+    let mut u = Vec::with_capacity(points.len());
+    let mut total = 0.0;
+    u.push(0.0);
+    for pair in points.windows(2) {
+        total += distance(pair[0], pair[1]);
+        u.push(total);
+    }
+    let last = points.len() - 1;
+    for (i, t) in u.iter_mut().enumerate() {
+        *t = if total > 0.0 { *t / total } else { i as f64 / last as f64 };
+    }
+    u
+}
+
+fn bernstein(t: f64) -> [f64; 4] {
+    let s = 1.0 - t;
+    [s * s * s, 3.0 * s * s * t, 3.0 * s * t * t, t * t * t]
+}
+
+fn evaluate(bezier: &[PointF64; 4], t: f64) -> PointF64 {
+    let [b0, b1, b2, b3] = bernstein(t);
+    scale(bezier[0], b0) + scale(bezier[1], b1) + scale(bezier[2], b2) + scale(bezier[3], b3)
+}
+
+/// Angle (in radians) the polyline turns by at `b`
+fn turn(a: PointF64, b: PointF64, c: PointF64) -> f64 {
+    let (v1, v2) = (b - a, c - b);
+    let norms = v1.norm() * v2.norm();
+    if norms == 0.0 {
+        0.0
+    } else {
+        (v1.dot(v2) / norms).clamp(-1.0, 1.0).acos()
+    }
+}
+
+fn scale(point: PointF64, factor: f64) -> PointF64 {
+    PointF64::new(point.x * factor, point.y * factor)
+}
+
+fn distance(a: PointF64, b: PointF64) -> f64 {
+    (b - a).norm()
+}
+
+fn normalize(point: PointF64) -> PointF64 {
+    let norm = point.norm();
+    if norm == 0.0 {
+        point
+    } else {
+        scale(point, 1.0 / norm)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f64::consts::PI;
+    use visioncortex::PathF64;
+
+    fn closed(mut points: Vec<PointF64>) -> PathF64 {
+        if let Some(&first) = points.first() {
+            points.push(first);
+        }
+        PathF64 { path: points }
+    }
+
+    /// points along every curve of `spline`
+    fn samples(spline: &Spline) -> Vec<PointF64> {
+        spline.points.windows(4).step_by(3).flat_map(|curve| {
+            let bezier = [curve[0], curve[1], curve[2], curve[3]];
+            (0..=100).map(move |i| evaluate(&bezier, i as f64 / 100.0))
+        }).collect()
+    }
+
+    /// how far `point` is from the sampled curve
+    fn miss(samples: &[PointF64], point: PointF64) -> f64 {
+        samples.iter().map(|sample| distance(*sample, point)).fold(f64::INFINITY, f64::min)
+    }
+
+    #[test]
+    fn curves_stay_within_tolerance() {
+        let circle: Vec<PointF64> = (0..64)
+            .map(|i| 2.0 * PI * i as f64 / 64.0)
+            .map(|angle| PointF64::new(50.0 + 20.0 * angle.cos(), 50.0 + 20.0 * angle.sin()))
+            .collect();
+        let spline = fit_closed(&closed(circle.clone()), PI / 2.0, 0.5);
+        assert!(spline.num_curves() < 8, "{} curves", spline.num_curves());
+        assert_eq!(spline.points.first(), spline.points.last());
+        let samples = samples(&spline);
+        for point in circle {
+            assert!(miss(&samples, point) < 0.5 + 0.05, "{:?} is off the curve", point);
+        }
+    }
+
+    #[test]
+    fn corners_split_curves() {
+        let corners = [(10.0, 10.0), (40.0, 10.0), (40.0, 40.0), (10.0, 40.0)];
+        let mut square = Vec::new();
+        for (i, &(x, y)) in corners.iter().enumerate() {
+            let (next_x, next_y) = corners[(i + 1) % 4];
+            for step in 0..10 {
+                let t = step as f64 / 10.0;
+                square.push(PointF64::new(x + (next_x - x) * t, y + (next_y - y) * t));
+            }
+        }
+        let spline = fit_closed(&closed(square.clone()), PI / 4.0, 0.5);
+        let ends: Vec<PointF64> = spline.points.iter().step_by(3).copied().collect();
+        for &(x, y) in corners.iter() {
+            assert!(ends.contains(&PointF64::new(x, y)), "no curve ends at ({}, {})", x, y);
+        }
+        let samples = samples(&spline);
+        for point in square {
+            assert!(miss(&samples, point) < 0.5 + 0.05, "{:?} is off the curve", point);
+        }
+    }
+
+    #[test]
+    fn degenerate_paths() {
+        let (a, b) = (PointF64::new(1.0, 2.0), PointF64::new(5.0, 2.0));
+        let empty = fit_closed(&PathF64 { path: Vec::new() }, PI / 2.0, 0.5);
+        assert_eq!(empty.num_curves(), 0);
+
+        let point = fit_closed(&closed(vec![a]), PI / 2.0, 0.5);
+        assert_eq!(point.points, vec![a, a, a, a]);
+
+        let segment = fit_closed(&closed(vec![a, b]), PI / 2.0, 0.5);
+        assert_eq!(segment.points, vec![a, b, b, b, a, a, a]);
+
+        // repeated points collapse
+        let repeated = fit_closed(&closed(vec![a, a, b, b]), PI / 2.0, 0.5);
+        assert_eq!(repeated.points, segment.points);
+    }
+}
@@ -0,0 +1,183 @@
+//! Image gradient along region boundaries, to keep regions apart across strong edges
+//!
+//! Two regions of similar color may still be different objects if the boundary between
+//! them is a sharp edge in the image. A boundary's strength is the mean gradient magnitude
+//! of the pixels on either side of it, from 0 (flat) to 1.
+use std::collections::HashMap;
+use std::hash::Hash;
+use visioncortex::ColorImage;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+use crate::Error;
+
+/// Criterion penalising merges across strong edges
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct EdgeParams {
+    /// Added to the color distance for each unit of boundary strength
+    pub weight: f64,
+    /// Merges across a boundary stronger than this are refused
+    pub threshold: f64,
+}
+
+/// Sobel gradient magnitude of every pixel, taking the strongest color channel; from 0 to 1
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct GradientImage {
+    pub pixels: Vec<f32>,
+    pub width: usize,
+    pub height: usize,
+}
+
+/// Accumulated strength of a boundary, pixel edge by pixel edge
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct BoundaryStrength {
+    pub sum: f64,
+    /// in pairs of adjacent pixels; pixel edges for 4-connectivity
+    pub length: usize,
+}
+
+/// [`EdgeParams`] with the gradient prepared for an input
+pub(crate) struct Edges {
+    weight: f64,
+    threshold: f64,
+    gradient: GradientImage,
+}
+
+impl Default for EdgeParams {
+    fn default() -> Self {
+        Self {
+            weight: 1.0,
+            threshold: f64::INFINITY,
+        }
+    }
+}
+
+impl EdgeParams {
+    pub(crate) fn validate(&self) -> Result<(), Error> {
+        if !(self.weight >= 0.0 && self.weight.is_finite() && self.threshold >= 0.0) {
+            return Err(Error::InvalidParams("edge"));
+        }
+        Ok(())
+    }
+}
+
+impl GradientImage {
+    /// from RGBA pixels
+    pub fn from_rgba(pixels: &[u8], width: usize, height: usize) -> Self {
+        // the largest response of a Sobel kernel
+        const SCALE: f32 = 4.0 * 255.0;
+        let at = |x: usize, y: usize, c: usize| pixels[(y * width + x) * 4 + c] as f32;
+        let mut gradient = vec![0.0; width * height];
+        for y in 0..height {
+            let (up, down) = (y.saturating_sub(1), std::cmp::min(y + 1, height - 1));
+            for x in 0..width {
+                let (left, right) = (x.saturating_sub(1), std::cmp::min(x + 1, width - 1));
+                let mut magnitude: f32 = 0.0;
+                for c in 0..3 {
+                    let gx = at(right, up, c) + 2.0 * at(right, y, c) + at(right, down, c)
+                        - at(left, up, c) - 2.0 * at(left, y, c) - at(left, down, c);
+                    let gy = at(left, down, c) + 2.0 * at(x, down, c) + at(right, down, c)
+                        - at(left, up, c) - 2.0 * at(x, up, c) - at(right, up, c);
+                    magnitude = magnitude.max(gx.hypot(gy));
+                }
+                gradient[y * width + x] = (magnitude / SCALE).min(1.0);
+            }
+        }
+        Self { pixels: gradient, width, height }
+    }
+
+    pub fn from_color_image(image: &ColorImage) -> Self {
+        Self::from_rgba(&image.pixels, image.width, image.height)
+    }
+
+    pub fn get_pixel(&self, x: usize, y: usize) -> f64 {
+        self.pixels[y * self.width + x] as f64
+    }
+
+    /// strength of the edge between two adjacent pixels, by index
+    fn between(&self, a: usize, b: usize) -> f64 {
+        (self.pixels[a] as f64 + self.pixels[b] as f64) / 2.0
+    }
+}
+
+impl BoundaryStrength {
+    pub fn add(&mut self, strength: f64) {
+        self.sum += strength;
+        self.length += 1;
+    }
+
+    pub fn merge(&mut self, other: &Self) {
+        self.sum += other.sum;
+        self.length += other.length;
+    }
+
+    pub fn mean(&self) -> f64 {
+        if self.length == 0 {
+            0.0
+        } else {
+            self.sum / self.length as f64
+        }
+    }
+}
+
+impl Edges {
+    /// measure on RGBA `pixels`
+    pub(crate) fn new(params: &EdgeParams, pixels: &[u8], width: u32, height: u32) -> Self {
+        Self {
+            weight: params.weight,
+            threshold: params.threshold,
+            gradient: GradientImage::from_rgba(pixels, width as usize, height as usize),
+        }
+    }
+
+    /// to be added to the color distance, or `None` if the merge is refused
+    pub(crate) fn penalty(&self, boundary: &BoundaryStrength) -> Option<f64> {
+        let strength = boundary.mean();
+        if strength > self.threshold {
+            None
+        } else {
+            Some(self.weight * strength)
+        }
+    }
+
+    /// strength of the edge between two adjacent pixels, by index
+    pub(crate) fn strength(&self, a: usize, b: usize) -> f64 {
+        self.gradient.between(a, b)
+    }
+
+    /// boundary of a region with each of its neighbours, sorted by label; `pixels` are the
+    /// region's, `labels` the label of every pixel, and `is_neighbour` tells which labels count
+    pub(crate) fn boundaries<T, F>(&self, pixels: &[u32], labels: &[T], is_neighbour: F) -> Vec<(T, BoundaryStrength)>
+    where
+        T: Copy + Ord + Hash,
+        F: Fn(T) -> bool,
+    {
+        let (width, height) = (self.gradient.width, self.gradient.height);
+        let mut boundaries: HashMap<T, BoundaryStrength> = HashMap::new();
+        for &i in pixels.iter() {
+            let i = i as usize;
+            let (x, y) = (i % width, i / width);
+            let mut add = |j: usize| {
+                if is_neighbour(labels[j]) {
+                    boundaries.entry(labels[j]).or_default().add(self.gradient.between(i, j));
+                }
+            };
+            if y > 0 {
+                add(i - width);
+            }
+            if y + 1 < height {
+                add(i + width);
+            }
+            if x > 0 {
+                add(i - 1);
+            }
+            if x + 1 < width {
+                add(i + 1);
+            }
+        }
+        let mut list: Vec<(T, BoundaryStrength)> = boundaries.into_iter().collect();
+        list.sort_by_key(|(label, _)| *label);
+        list
+    }
+}
@@ -0,0 +1,34 @@
+//! Errors reported by Processors
+use std::fmt;
+
+/// Reason a Processor rejected a call
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    /// A parameter is out of its valid range; carries the parameter name
+    InvalidParams(&'static str),
+    /// The input image has no pixels or the clusters contain no shape
+    EmptyInput,
+    /// The call is not allowed at the current stage, e.g. ticking before input
+    WrongStage(&'static str),
+    /// The output has already been taken
+    OutputTaken,
+    /// The snapshot does not belong to the given input
+    SnapshotMismatch,
+    /// The snapshot is inconsistent in itself, e.g. refers to regions it does not contain
+    InvalidSnapshot,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidParams(name) => write!(f, "invalid parameter: {}", name),
+            Self::EmptyInput => write!(f, "input is empty"),
+            Self::WrongStage(reason) => write!(f, "wrong stage: {}", reason),
+            Self::OutputTaken => write!(f, "output has already been taken"),
+            Self::SnapshotMismatch => write!(f, "snapshot does not match input"),
+            Self::InvalidSnapshot => write!(f, "snapshot is inconsistent"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
@@ -1,14 +1,7 @@
 pub type Bitmask = Vec<u32>;
 #[inline]
 pub fn new(size: usize) -> Bitmask {
-    vec![
-        0;
-        if size % 32 == 0 {
-            size / 32
-        } else {
-            size / 32 + 1
-        }
-    ]
+    vec![0; size.div_ceil(32)]
 }
 
 #[inline]
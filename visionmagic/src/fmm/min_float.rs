@@ -1,18 +1,21 @@
 use std::cmp;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
 
 #[derive(PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct MinFloat(pub f32);
 
 impl Eq for MinFloat {}
 
 impl PartialOrd for MinFloat {
     fn partial_cmp(&self, other: &Self) -> Option<cmp::Ordering> {
-        other.0.partial_cmp(&self.0)
+        Some(self.cmp(other))
     }
 }
 
 impl Ord for MinFloat {
     fn cmp(&self, other: &Self) -> cmp::Ordering {
-        self.partial_cmp(other).unwrap()
+        other.0.partial_cmp(&self.0).unwrap()
     }
 }
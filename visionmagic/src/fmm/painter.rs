@@ -1,7 +1,13 @@
 use super::bitmask;
 use super::min_float::MinFloat;
+use crate::clock::{self, Clock, SystemClock};
 use std::collections::BinaryHeap;
+use std::time::Duration;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
 #[derive(Default)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Image {
     pub buf: Vec<u8>,
     pub width: u32,
@@ -9,12 +15,16 @@ pub struct Image {
 }
 
 #[derive(PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Node {
     pub priority: MinFloat,
     pub i: u32,
 }
 
+/// The Painter holds all of its in-flight state;
+/// with the `serde` feature it can be serialized mid-way and resumed by calling `paint()` again
 #[derive(Default)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Painter {
     pub im: Image,
     pub inside: bitmask::Bitmask,
@@ -70,6 +80,20 @@ impl Painter {
     pub fn paint(self) -> Self {
         paint(self.im, self.inside, self.times, self.queue, self.count, self.max_queue, self.progress)
     }
+
+    /// Keep painting until finished or `budget` is used up
+    pub fn paint_for(self, budget: Duration) -> Self {
+        self.paint_for_with(budget, &SystemClock::new())
+    }
+
+    /// Same as [`Painter::paint_for`], measuring time with the given clock
+    pub fn paint_for_with<C: Clock>(mut self, budget: Duration, clock: &C) -> Self {
+        clock::run_for(budget, clock, || {
+            self = std::mem::take(&mut self).paint();
+            self.progress == 100
+        });
+        self
+    }
 }
 
 macro_rules! paint_detail {
@@ -184,13 +208,13 @@ fn inpaint(im: &mut Image, inside: &[u32], times: &[f32], p: usize) {
     let down = std::cmp::max(point.1 + radius, im.height as i32 - 1);
     let mut sum = [0.0; 3];
     let mut total = 0.0;
-    let mut y = up as i32;
+    let mut y = up;
 
     while y < down {
-        let mut x = left as i32;
+        let mut x = left;
         while x < right {
-            let dx = x - point.0 as i32;
-            let dy = y - point.1 as i32;
+            let dx = x - point.0;
+            let dy = y - point.1;
             if (x != point.0 || y != point.1)
                 && dx * dx + dy * dy <= r_sq
                 && !bitmask::get(inside, index!(im, x, y))
@@ -1,4 +1,5 @@
 use super::painter::*;
+use crate::parallel;
 
 pub struct Smoother {
     pub im: Image,
@@ -30,17 +31,19 @@ impl Smoother {
         let radius = self.blurriness;
         assert!(im.width > 2 * radius);
         assert!(im.height > 2 * radius);
-        let mut overlay = Vec::new();
-        for y in radius..im.height - radius {
+        let rows: Vec<u32> = (radius..im.height - radius).collect();
+        let overlay: Vec<(u32, u32, u32)> = parallel::map(&rows, |&y| {
+            let mut row = Vec::new();
             for x in radius..im.width - radius {
                 if inside!(mask, im.width, x, y) {
-                    // overlay.push((x, y, Self::radial_blur(&im, x, y, radius)));
-                    // overlay.push((x, y, Self::radial_blur_edge(&im, mask, x, y, radius)));
-                    // overlay.push((x, y, Self::radial_blur_edge_peel(&im, mask, x, y, radius)));
-                    overlay.push((x, y, Self::radial_blur_edge_peel_var(&im, mask, x, y, radius)));
+                    // row.push((x, y, Self::radial_blur(&im, x, y, radius)));
+                    // row.push((x, y, Self::radial_blur_edge(&im, mask, x, y, radius)));
+                    // row.push((x, y, Self::radial_blur_edge_peel(&im, mask, x, y, radius)));
+                    row.push((x, y, Self::radial_blur_edge_peel_var(&im, mask, x, y, radius)));
                 }
             }
-        }
+            row
+        }).into_iter().flatten().collect();
         for o in overlay.iter() {
             *elem!(im, o.0, o.1, 0) = ((o.2 >> 24) & 0xFF) as u8;
             *elem!(im, o.0, o.1, 1) = ((o.2 >> 16) & 0xFF) as u8;
@@ -48,12 +51,7 @@ impl Smoother {
         }
         if true {
             // must apply denoise if using radial_blur_edge_peel_var
-            let mut denoise = Vec::new();
-            for o in overlay.iter() {
-                let x = o.0;
-                let y = o.1;
-                denoise.push((x, y, Self::denoise(&im, x, y)));
-            }
+            let denoise = parallel::map(&overlay, |o| (o.0, o.1, Self::denoise(&im, o.0, o.1)));
             for o in denoise.iter() {
                 *elem!(im, o.0, o.1, 0) = ((o.2 >> 24) & 0xFF) as u8;
                 *elem!(im, o.0, o.1, 1) = ((o.2 >> 16) & 0xFF) as u8;
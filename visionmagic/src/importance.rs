@@ -0,0 +1,114 @@
+//! Per-pixel importance, to keep small but salient details from merging away
+//!
+//! A region's importance is the mean importance of its pixels, so a region merely touching
+//! an important area is hardly affected. A merge counts as important as the more important
+//! of the two regions.
+use visioncortex::BinaryImage;
+
+use crate::Error;
+
+/// Importance of every pixel, from 0 (none) to 1
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ImportanceMap {
+    pub pixels: Vec<f32>,
+    pub width: usize,
+    pub height: usize,
+}
+
+/// Criterion discouraging or refusing merges of important regions
+#[derive(Clone, Debug, PartialEq)]
+pub struct Protection {
+    pub map: ImportanceMap,
+    /// Added to the color distance for each unit of importance
+    pub weight: f64,
+    /// Merges more important than this are refused, whatever the size of the regions
+    pub threshold: f64,
+}
+
+impl ImportanceMap {
+    pub fn new_w_h(width: usize, height: usize) -> Self {
+        Self {
+            pixels: vec![0.0; width * height],
+            width,
+            height,
+        }
+    }
+
+    /// fully important where `mask` is set
+    pub fn from_mask(mask: &BinaryImage) -> Self {
+        let mut map = Self::new_w_h(mask.width, mask.height);
+        for y in 0..mask.height {
+            for x in 0..mask.width {
+                if mask.get_pixel(x, y) {
+                    map.set_pixel(x, y, 1.0);
+                }
+            }
+        }
+        map
+    }
+
+    pub fn get_pixel(&self, x: usize, y: usize) -> f32 {
+        self.pixels[y * self.width + x]
+    }
+
+    pub fn set_pixel(&mut self, x: usize, y: usize, importance: f32) {
+        self.pixels[y * self.width + x] = importance;
+    }
+
+    /// summed over the pixels at `indices`
+    pub fn sum(&self, indices: &[u32]) -> f64 {
+        indices.iter().map(|&i| self.pixels[i as usize] as f64).sum()
+    }
+}
+
+impl Protection {
+    /// refusing merges of regions mostly within the important area of `map`
+    pub fn new(map: ImportanceMap) -> Self {
+        Self {
+            map,
+            weight: 1.0,
+            threshold: 0.5,
+        }
+    }
+
+    pub(crate) fn validate(&self) -> Result<(), Error> {
+        let valid = self.weight >= 0.0 && self.weight.is_finite() && self.threshold >= 0.0 &&
+            self.map.pixels.len() == self.map.width * self.map.height &&
+            self.map.pixels.iter().all(|importance| (0.0..=1.0).contains(importance));
+        if !valid {
+            return Err(Error::InvalidParams("protection"));
+        }
+        Ok(())
+    }
+
+    /// the map has to be of the same size as the input
+    pub(crate) fn check_size(&self, width: u32, height: u32) -> Result<(), Error> {
+        if self.map.width != width as usize || self.map.height != height as usize {
+            return Err(Error::InvalidParams("protection"));
+        }
+        Ok(())
+    }
+
+    pub(crate) fn refuses(&self, importance: f64) -> bool {
+        importance > self.threshold
+    }
+
+    /// to be added to the color distance
+    pub(crate) fn penalty(&self, importance: f64) -> f64 {
+        self.weight * importance
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validate_rejects_importance_out_of_range() {
+        for (importance, valid) in [(0.0, true), (1.0, true), (-0.1, false), (1.5, false), (f32::NAN, false), (f32::INFINITY, false)] {
+            let mut map = ImportanceMap::new_w_h(2, 2);
+            map.set_pixel(1, 1, importance);
+            assert_eq!(Protection::new(map).validate().is_ok(), valid, "{}", importance);
+        }
+    }
+}
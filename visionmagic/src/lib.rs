@@ -2,16 +2,30 @@
 pub use visioncortex;
 
 pub mod aggregation;
+pub mod clock;
 pub mod cluster_stat;
 pub mod clustering;
+pub mod color_metric;
+mod curve_fit;
+pub mod edge;
+mod error;
 pub mod fmm;
+pub mod importance;
+mod parallel;
 mod pipeline;
+pub mod region;
 pub mod segmentation;
+#[cfg(feature = "serde")]
+mod serde_remote;
 pub mod simplification;
+pub mod stats;
+#[cfg(test)]
+mod testing;
 
 pub use aggregation::Processor as Aggregation;
 pub use cluster_stat::Processor as ClusterStat;
 pub use clustering::Processor as Clustering;
+pub use error::Error;
 pub use pipeline::*;
 pub use segmentation::Processor as Segmentation;
 pub use simplification::Processor as Simplification;
\ No newline at end of file
@@ -0,0 +1,94 @@
+//! Data-parallel helpers; work is spread over threads with the `parallel` feature
+//! and runs serially otherwise. Results always come back in input order, so the output
+//! is identical either way.
+
+#[cfg(test)]
+use std::cell::Cell;
+
+#[cfg(feature = "parallel")]
+const BATCH: usize = 64;
+#[cfg(not(feature = "parallel"))]
+const BATCH: usize = 1;
+
+#[cfg(test)]
+thread_local! {
+    static BATCH_OVERRIDE: Cell<Option<usize>> = const { Cell::new(None) };
+}
+
+/// Number of units of work a Processor performs per tick
+pub(crate) fn batch() -> usize {
+    #[cfg(test)]
+    {
+        if let Some(batch) = BATCH_OVERRIDE.with(Cell::get) {
+            return batch;
+        }
+    }
+    BATCH
+}
+
+/// Run `f` with the Processors on this thread performing `batch` units of work per tick
+#[cfg(test)]
+pub(crate) fn with_batch<R>(batch: usize, f: impl FnOnce() -> R) -> R {
+    let previous = BATCH_OVERRIDE.with(|cell| cell.replace(Some(batch)));
+    let result = f();
+    BATCH_OVERRIDE.with(|cell| cell.set(previous));
+    result
+}
+
+/// Apply `f` to every item, preserving order
+pub(crate) fn map<T, R, F>(items: &[T], f: F) -> Vec<R>
+where
+    T: Sync,
+    R: Send,
+    F: Fn(&T) -> R + Sync + Send,
+{
+    #[cfg(feature = "parallel")]
+    {
+        use rayon::prelude::*;
+        items.par_iter().map(f).collect()
+    }
+    #[cfg(not(feature = "parallel"))]
+    {
+        items.iter().map(f).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    //! Each test runs a Processor a unit of work per tick, which is the serial path, and again
+    //! in batches, which with the `parallel` feature are spread over threads; the outputs must be equal
+    use super::with_batch;
+    use crate::segmentation::RegionCount;
+    use crate::testing;
+    use crate::{Processor, Segmentation, Simplification};
+
+    #[test]
+    fn segmentation() {
+        for count in [None, Some(RegionCount::exact(8))] {
+            let run = |batch| with_batch(batch, || {
+                let mut processor = Segmentation::new();
+                processor.config(crate::segmentation::Params {
+                    deviation: 0.6,
+                    count,
+                    ..Default::default()
+                }).unwrap();
+                processor.input(testing::clusters(testing::image(256, 256, 1))).unwrap();
+                while !processor.tick() {}
+                processor.output().unwrap().pixels
+            });
+            assert!(run(1) == run(64), "{:?}", count);
+        }
+    }
+
+    #[test]
+    fn simplification() {
+        let run = |batch| with_batch(batch, || {
+            let mut processor = Simplification::new();
+            processor.input(testing::clusters(testing::image(256, 256, 2))).unwrap();
+            while !processor.tick() {}
+            let shapes = processor.output().unwrap();
+            processor.to_svg(&shapes, None).unwrap()
+        });
+        assert!(run(1) == run(64));
+    }
+}
@@ -1,3 +1,7 @@
+use std::time::Duration;
+use crate::Error;
+use crate::clock::{self, Clock, SystemClock};
+
 /// Processor is an element of an image processing pipeline
 pub trait Processor {
 
@@ -11,19 +15,320 @@ pub trait Processor {
     /// Create a new Processor instance
     fn new() -> Self;
 
-    /// Configure parameters; returns true for valid config
-    fn config(&mut self, params: Self::Params) -> bool;
+    /// Configure parameters; returns an error for invalid config
+    fn config(&mut self, params: Self::Params) -> Result<(), Error>;
 
-    /// Provide input to Processor; returns true for valid input
-    fn input(&mut self, input: Self::Input) -> bool;
+    /// Provide input to Processor; returns an error for invalid input
+    fn input(&mut self, input: Self::Input) -> Result<(), Error>;
 
     /// Handover control to Processor to perform one unit of work; returns true when finished
+    /// (or when there is nothing to work on)
     fn tick(&mut self) -> bool;
 
+    /// Keep ticking until finished or `budget` is used up; returns true when finished
+    fn tick_for(&mut self, budget: Duration) -> bool {
+        self.tick_for_with(budget, &SystemClock::new())
+    }
+
+    /// Same as [`Processor::tick_for`], measuring time with the given clock
+    fn tick_for_with<C: Clock>(&mut self, budget: Duration, clock: &C) -> bool {
+        clock::run_for(budget, clock, || self.tick())
+    }
+
     /// Check progress; returns an integer from 0 to 100 (inclusive)
     fn progress(&self) -> u32;
 
     /// Retrieve output from Processor
-    fn output(&mut self) -> Self::Output;
+    fn output(&mut self) -> Result<Self::Output, Error>;
+
+    /// Chain another Processor after this one, feeding our output into its input
+    fn chain<B>(self, next: B) -> Chain<Self, B>
+    where
+        Self: Sized,
+        B: Processor<Input = Self::Output>,
+    {
+        Chain::from_pair(self, next)
+    }
+
+}
+
+/// Overall progress, from 0 to 100, of stages run one after another and weighted by their relative
+/// cost, while the `stage`-th (from 0) is at `progress`. Stages weigh the same if all weights are
+/// zero; with no stages at all, there is nothing left to do
+pub fn staged_progress(weights: &[u32], stage: usize, progress: u32) -> u32 {
+    let equal = weights.iter().all(|&weight| weight == 0);
+    let weight = |weight: &u32| if equal { 1 } else { *weight as u64 };
+    let done: u64 = weights.iter().take(stage).map(weight).sum();
+    let current = weights.get(stage).map_or(0, |w| weight(w) * progress.min(100) as u64);
+    let total: u64 = weights.iter().map(weight).sum();
+    if total == 0 {
+        return 100;
+    }
+    ((done * 100 + current) / total) as u32
+}
+
+/// Two Processors joined together; the output of `A` becomes the input of `B`.
+///
+/// A `Chain` is itself a [`Processor`], so chains can be nested to form a whole pipeline:
+///
+/// ```
+/// use visionmagic::{Aggregation, Chain, Clustering, Processor, Segmentation};
+///
+/// type Impression = Chain<Chain<Chain<Clustering, Segmentation>, Clustering>, Aggregation>;
+///
+/// let pipeline = Impression::new();
+/// assert!(!pipeline.in_second_stage());
+/// ```
+pub struct Chain<A, B> {
+    first: A,
+    second: B,
+    weights: (u32, u32),
+    stage: ChainStage,
+    error: Option<Error>,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum ChainStage {
+    First,
+    Second,
+}
+
+impl<A, B> Chain<A, B>
+where
+    A: Processor,
+    B: Processor<Input = A::Output>,
+{
+    /// Join two (possibly pre-configured) Processors together
+    pub fn from_pair(first: A, second: B) -> Self {
+        Self {
+            first,
+            second,
+            weights: (1, 1),
+            stage: ChainStage::First,
+            error: None,
+        }
+    }
+
+    /// Set the relative cost of each stage, used to combine their progress;
+    /// both stages weigh the same by default, or if both weights are zero
+    pub fn with_weights(mut self, first: u32, second: u32) -> Self {
+        self.weights = (first, second);
+        self
+    }
+
+    pub fn first(&self) -> &A {
+        &self.first
+    }
+
+    pub fn first_mut(&mut self) -> &mut A {
+        &mut self.first
+    }
+
+    pub fn second(&self) -> &B {
+        &self.second
+    }
+
+    pub fn second_mut(&mut self) -> &mut B {
+        &mut self.second
+    }
+
+    /// Returns true once the first stage has finished and handed over its output
+    pub fn in_second_stage(&self) -> bool {
+        self.stage == ChainStage::Second
+    }
+
+    /// Go back to ticking the first stage, whose output is handed over again once it finishes;
+    /// the first stage has to be restarted, e.g. by reconfiguring it, beforehand
+    pub fn rewind(&mut self) {
+        self.stage = ChainStage::First;
+        self.error = None;
+    }
+}
+
+impl<A, B> Processor for Chain<A, B>
+where
+    A: Processor,
+    B: Processor<Input = A::Output>,
+{
+
+    type Input = A::Input;
+    type Output = B::Output;
+    type Params = (A::Params, B::Params);
+
+    fn new() -> Self {
+        Self::from_pair(A::new(), B::new())
+    }
+
+    /// configure both stages
+    fn config(&mut self, params: Self::Params) -> Result<(), Error> {
+        self.first.config(params.0)?;
+        self.second.config(params.1)
+    }
+
+    fn input(&mut self, input: Self::Input) -> Result<(), Error> {
+        self.stage = ChainStage::First;
+        self.error = None;
+        self.first.input(input)
+    }
+
+    /// an error during handover finishes the chain; it is reported by `output()`
+    fn tick(&mut self) -> bool {
+        match self.stage {
+            ChainStage::First => {
+                if self.first.tick() {
+                    let handover = self.first.output().and_then(|intermediate| {
+                        self.second.input(intermediate)
+                    });
+                    self.stage = ChainStage::Second;
+                    if let Err(error) = handover {
+                        self.error = Some(error);
+                        return true;
+                    }
+                }
+                false
+            },
+            ChainStage::Second => self.error.is_some() || self.second.tick(),
+        }
+    }
+
+    fn progress(&self) -> u32 {
+        let (a, b) = self.weights;
+        match self.stage {
+            ChainStage::First => staged_progress(&[a, b], 0, self.first.progress()),
+            ChainStage::Second => staged_progress(&[a, b], 1, self.second.progress()),
+        }
+    }
+
+    fn output(&mut self) -> Result<Self::Output, Error> {
+        if let Some(error) = self.error.take() {
+            return Err(error);
+        }
+        self.second.output()
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// ticks as many times as its input says, then outputs twice the input; rejects the input
+    /// it is configured with
+    #[derive(Default)]
+    struct Counter {
+        reject: Option<u32>,
+        inputs: Vec<u32>,
+        target: u32,
+        ticks: u32,
+    }
+
+    impl Processor for Counter {
+        type Input = u32;
+        type Output = u32;
+        type Params = Option<u32>;
+
+        fn new() -> Self {
+            Self::default()
+        }
+
+        fn config(&mut self, reject: Option<u32>) -> Result<(), Error> {
+            self.reject = reject;
+            Ok(())
+        }
+
+        fn input(&mut self, input: u32) -> Result<(), Error> {
+            if self.reject == Some(input) {
+                return Err(Error::InvalidParams("input"));
+            }
+            self.inputs.push(input);
+            self.target = input;
+            self.ticks = 0;
+            Ok(())
+        }
+
+        fn tick(&mut self) -> bool {
+            self.ticks = std::cmp::min(self.ticks + 1, self.target);
+            self.ticks == self.target
+        }
+
+        fn progress(&self) -> u32 {
+            (self.ticks * 100).checked_div(self.target).unwrap_or(100)
+        }
+
+        fn output(&mut self) -> Result<u32, Error> {
+            Ok(self.target * 2)
+        }
+    }
+
+    type Pair = Chain<Counter, Counter>;
+
+    /// ticks until finished, and then some
+    fn run(chain: &mut Pair) -> usize {
+        let mut ticks = 1;
+        while !chain.tick() {
+            ticks += 1;
+        }
+        for _ in 0..3 {
+            assert!(chain.tick());
+        }
+        ticks
+    }
+
+    #[test]
+    fn hands_over_once() {
+        let mut chain = Pair::new();
+        chain.input(3).unwrap();
+        // the handover happens on the last tick of the first stage
+        assert_eq!(run(&mut chain), 3 + 6);
+        assert_eq!(chain.first().inputs, [3]);
+        assert_eq!(chain.second().inputs, [6]);
+        assert_eq!(chain.output(), Ok(12));
+    }
+
+    #[test]
+    fn handover_error_finishes() {
+        let mut chain = Pair::new();
+        chain.config((None, Some(6))).unwrap();
+        chain.input(3).unwrap();
+        assert_eq!(run(&mut chain), 3);
+        assert!(chain.second().inputs.is_empty());
+        assert_eq!(chain.output(), Err(Error::InvalidParams("input")));
+    }
+
+    #[test]
+    fn rewind_restarts_first_stage() {
+        let mut chain = Pair::new();
+        chain.input(3).unwrap();
+        run(&mut chain);
+        chain.first_mut().input(5).unwrap();
+        chain.rewind();
+        assert!(!chain.in_second_stage());
+        assert_eq!(run(&mut chain), 5 + 10);
+        assert_eq!(chain.second().inputs, [6, 10]);
+        assert_eq!(chain.output(), Ok(20));
+    }
+
+    #[test]
+    fn progress_is_monotone() {
+        for (first, second) in [(1, 1), (3, 1), (0, 5), (0, 0)] {
+            let mut chain = Pair::new().with_weights(first, second);
+            chain.input(4).unwrap();
+            let mut last = chain.progress();
+            while !chain.tick() {
+                let progress = chain.progress();
+                assert!(last <= progress && progress < 100, "{} then {} at {:?}", last, progress, (first, second));
+                last = progress;
+            }
+            assert_eq!(chain.progress(), 100, "{:?}", (first, second));
+        }
+    }
 
-}
\ No newline at end of file
+    #[test]
+    fn staged_progress_without_weights() {
+        assert_eq!(staged_progress(&[], 0, 50), 100);
+        assert_eq!(staged_progress(&[0, 0], 0, 50), 25);
+        assert_eq!(staged_progress(&[0, 0], 1, 100), 100);
+        assert_eq!(staged_progress(&[0, 3], 0, 100), 0);
+        assert_eq!(staged_progress(&[u32::MAX, u32::MAX], 1, 100), 100);
+    }
+}
@@ -0,0 +1,149 @@
+//! Per-pixel region labels, an alternative to the re-coloured image output
+use std::collections::BTreeMap;
+use visioncortex::{BoundingRect, Color};
+
+/// Region id of every pixel, in row-major order
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct LabelImage {
+    pub pixels: Vec<u32>,
+    pub width: usize,
+    pub height: usize,
+}
+
+/// Summary of one region of a [`LabelImage`]
+#[derive(Clone, Debug, PartialEq)]
+pub struct Region {
+    /// Label of the region's pixels; also its position in [`RegionMap::regions`]
+    pub id: u32,
+    /// Number of pixels
+    pub area: usize,
+    /// Mean colour, whatever the region is painted with in the image output
+    pub color: Color,
+    pub rect: BoundingRect,
+}
+
+/// [`LabelImage`] with the table of its regions
+#[derive(Clone, Debug, Default)]
+pub struct RegionMap {
+    pub labels: LabelImage,
+    pub regions: Vec<Region>,
+}
+
+/// Region adjacency graph; nodes are indexed by region id
+#[derive(Clone, Debug, Default)]
+pub struct RegionGraph {
+    pub nodes: Vec<Region>,
+    /// sorted by `(a, b)`
+    pub edges: Vec<Edge>,
+}
+
+/// Adjacency between two regions
+#[derive(Clone, Debug, PartialEq)]
+pub struct Edge {
+    /// The smaller of the two region ids
+    pub a: u32,
+    /// The larger of the two region ids
+    pub b: u32,
+    /// Length of the shared boundary, in pixel edges (4-connectivity)
+    pub boundary: usize,
+    /// Distance between the region colours, in the metric of the producing Processor
+    pub color_distance: f64,
+}
+
+impl LabelImage {
+    /// Pixels not belonging to any region
+    pub const UNLABELED: u32 = u32::MAX;
+
+    pub fn new_w_h(width: usize, height: usize) -> Self {
+        Self {
+            pixels: vec![Self::UNLABELED; width * height],
+            width,
+            height,
+        }
+    }
+
+    pub fn get_pixel(&self, x: usize, y: usize) -> u32 {
+        self.pixels[y * self.width + x]
+    }
+
+    pub fn set_pixel(&mut self, x: usize, y: usize, label: u32) {
+        self.pixels[y * self.width + x] = label;
+    }
+}
+
+impl RegionMap {
+    /// Build from groups of pixel indices and their colours;
+    /// ids are assigned in the order of groups, skipping empty ones
+    pub(crate) fn from_groups<I, G>(width: u32, height: u32, groups: I) -> Self
+    where
+        I: IntoIterator<Item = (Color, G)>,
+        G: IntoIterator<Item = u32>,
+    {
+        let mut labels = LabelImage::new_w_h(width as usize, height as usize);
+        let mut regions = Vec::new();
+        for (color, pixels) in groups {
+            let id = regions.len() as u32;
+            let mut area = 0;
+            let mut rect = BoundingRect::default();
+            for px in pixels {
+                labels.pixels[px as usize] = id;
+                rect.add_x_y((px % width) as i32, (px / width) as i32);
+                area += 1;
+            }
+            if area > 0 {
+                regions.push(Region { id, area, color, rect });
+            }
+        }
+        Self { labels, regions }
+    }
+}
+
+impl RegionMap {
+    /// Length of the shared boundary, in pixel edges, of every pair of adjacent regions;
+    /// keyed by `(smaller id, larger id)`
+    pub fn boundaries(&self) -> BTreeMap<(u32, u32), usize> {
+        let labels = &self.labels;
+        let mut boundaries = BTreeMap::new();
+        let mut add = |a: u32, b: u32| {
+            if a != b && a != LabelImage::UNLABELED && b != LabelImage::UNLABELED {
+                *boundaries.entry((a.min(b), a.max(b))).or_insert(0) += 1;
+            }
+        };
+        for y in 0..labels.height {
+            for x in 0..labels.width {
+                let label = labels.get_pixel(x, y);
+                if x + 1 < labels.width {
+                    add(label, labels.get_pixel(x + 1, y));
+                }
+                if y + 1 < labels.height {
+                    add(label, labels.get_pixel(x, y + 1));
+                }
+            }
+        }
+        boundaries
+    }
+}
+
+impl RegionGraph {
+    /// Build from the adjacency of `map`, measuring colour distance with `distance`
+    pub(crate) fn from_map<F>(map: RegionMap, distance: F) -> Self
+    where
+        F: Fn(Color, Color) -> f64,
+    {
+        let edges = map.boundaries().into_iter().map(|((a, b), boundary)| Edge {
+            a,
+            b,
+            boundary,
+            color_distance: distance(map.regions[a as usize].color, map.regions[b as usize].color),
+        }).collect();
+        Self {
+            nodes: map.regions,
+            edges,
+        }
+    }
+
+    /// Edges incident to the region `id`
+    pub fn edges_of(&self, id: u32) -> impl Iterator<Item = &Edge> {
+        self.edges.iter().filter(move |edge| edge.a == id || edge.b == id)
+    }
+}
@@ -1,8 +1,18 @@
 //! Processor to group clusters together by the disjoint set algorithm
-use std::collections::HashMap;
-use visioncortex::{Color, ColorImage, ColorSum};
-use visioncortex::color_clusters::{Cluster, Clusters, ClusterIndex};
-use visioncortex::disjoint_sets::Forests;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use visioncortex::{BinaryImage, Color, ColorImage, ColorSum};
+use visioncortex::color_clusters::{Clusters, ClusterIndex, ClustersView};
+use visioncortex::disjoint_sets::{Forests, Label};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use crate::Error;
+use crate::color_metric::{ColorMetric, Hsv};
+use crate::edge::{BoundaryStrength, EdgeParams, Edges};
+use crate::importance::Protection;
+use crate::stats::{self, RegionStats, Srm};
+use crate::region::{RegionGraph, RegionMap};
+use crate::parallel;
 use crate::pipeline::Processor as ProcessorTrait;
 
 #[derive(Default)]
@@ -11,6 +21,17 @@ pub struct Processor {
     clusters: Option<Input>,
     forests: Forests<ClusterIndex>,
     counter: usize,
+    /// tick has returned true since the last input
+    finished: bool,
+    merger: Option<Box<Merger>>,
+    history: Vec<Union>,
+    /// scribble label of each scribbled cluster
+    seeds: HashMap<ClusterIndex, u32>,
+    /// scribble label of each set containing a scribbled cluster
+    set_seeds: HashMap<Label, u32>,
+    edges: Option<Edges>,
+    /// mean importance of each cluster, by index, if protecting
+    importance: Vec<f64>,
 }
 
 /// [`Clusters`]
@@ -19,10 +40,249 @@ pub type Input = Clusters;
 /// [`ColorImage`]
 pub type Output = ColorImage;
 
-#[derive(Default)]
+/// In-flight state of a [`Processor`]; the input clusters and params are not included
+/// and have to be provided again on restore
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Snapshot {
+    width: u32,
+    height: u32,
+    counter: usize,
+    finished: bool,
+    /// set label of each output cluster
+    labels: Vec<Label>,
+    history: Vec<Union>,
+}
+
+/// Union of the sets of two clusters, which happens at `distance` or any larger deviation
+#[derive(Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+struct Union {
+    a: u32,
+    b: u32,
+    distance: f64,
+}
+
+/// Dendrogram of the unions made by a [`Processor`], which can be cut at any threshold.
+/// Leaves `0..leaves` are the input's output clusters in order; merge `i` creates node `leaves + i`
+#[derive(Clone, Debug, Default)]
+pub struct MergeTree {
+    pub leaves: usize,
+    /// area of each leaf
+    pub areas: Vec<usize>,
+    /// in ascending order of distance
+    pub merges: Vec<Merge>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Merge {
+    /// node merged
+    pub a: usize,
+    /// node merged
+    pub b: usize,
+    /// smallest deviation at which the merge happens; for [`RegionCount`] mode,
+    /// the largest color distance of this and all previous merges
+    pub distance: f64,
+    /// area of the resulting node
+    pub area: usize,
+}
+
 pub struct Params {
-    /// Allowed color difference between shapes in same set
+    /// Allowed color difference between shapes in same set, in the unit of `metric`
     pub deviation: f64,
+    /// How far a cluster votes down its neighbours, closest first
+    pub ladder: VoteLadder,
+    /// How color difference is measured
+    pub metric: Box<dyn ColorMetric>,
+    /// If set, merge the most similar adjacent sets first until their number is within range,
+    /// instead of voting by `deviation`
+    pub count: Option<RegionCount>,
+    /// Clusters under scribbles of the same label always end up in the same set,
+    /// and never in the same set as those under a different label
+    pub scribbles: Vec<Scribble>,
+    /// If set, penalise or refuse merges across strong edges in the image
+    pub edge: Option<EdgeParams>,
+    /// If set, merge the most similar adjacent sets first for as long as they pass this test,
+    /// instead of voting by `deviation`. Along with `count`, it replaces `deviation` there
+    pub srm: Option<Srm>,
+    /// Image to measure edges and texture on, instead of the input's own; must be of the same size
+    pub source: Option<ColorImage>,
+    /// If set, penalise or refuse merges of important sets; the map must be of the same size
+    /// as the input. Scribbles still apply
+    pub protection: Option<Protection>,
+}
+
+/// A cluster unions with its closest neighbour if within `deviation` times the first factor,
+/// then with the next closest if also within `deviation` times the second, and so on;
+/// the last factor applies to all further neighbours
+#[derive(Clone, Debug, PartialEq)]
+pub struct VoteLadder {
+    pub factors: Vec<f64>,
+}
+
+/// Pixels marked by the user as belonging to the same object
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Scribble {
+    pub label: u32,
+    /// (x, y) of each pixel
+    pub points: Vec<(u32, u32)>,
+}
+
+/// Range of the number of sets to produce. Merging always continues down to `max`,
+/// then down to `min` for as long as the sets to merge are within `deviation`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RegionCount {
+    pub min: usize,
+    pub max: usize,
+}
+
+/// Regions and their adjacency, for merging in order of color distance
+struct Merger {
+    /// regions keyed by their smallest cluster index, which does not depend on merge order
+    nodes: HashMap<u32, Node>,
+    /// region key of each set label; only used while scanning
+    keys: HashMap<Label, u32>,
+    /// candidate merges by color distance then keys; entries go stale as regions merge
+    queue: BinaryHeap<Reverse<(i32, u32, u32)>>,
+    /// number of regions before merging
+    initial: usize,
+    scanned: bool,
+    finished: bool,
+}
+
+struct Node {
+    sum: ColorSum,
+    /// in pixels
+    area: u64,
+    /// if testing with `srm`
+    stats: Option<RegionStats>,
+    /// summed over the pixels, if protecting
+    importance: f64,
+    neighbours: HashMap<u32, BoundaryStrength>,
+    /// scribble label
+    seed: Option<u32>,
+}
+
+/// How sets are compared; distances are in units of 1/10000 of the metric
+struct Measure<'a> {
+    metric: &'a dyn ColorMetric,
+    edges: Option<&'a Edges>,
+    srm: Option<&'a Srm>,
+    /// of the image, for `srm`
+    area: usize,
+    protection: Option<&'a Protection>,
+    /// mean importance of each cluster, by index, if protecting
+    importance: &'a [f64],
+}
+
+/// Merges performed by a tick in [`RegionCount`] mode
+const MERGES_PER_TICK: usize = 256;
+
+impl Default for Params {
+    fn default() -> Self {
+        Self {
+            deviation: 0.0,
+            ladder: VoteLadder::default(),
+            metric: Box::new(Hsv::new(2.0, 1.0, 1.0)),
+            count: None,
+            scribbles: Vec::new(),
+            edge: None,
+            srm: None,
+            source: None,
+            protection: None,
+        }
+    }
+}
+
+impl Params {
+    fn validate(&self) -> Result<(), Error> {
+        if !(self.deviation >= 0.0 && self.deviation.is_finite()) {
+            return Err(Error::InvalidParams("deviation"));
+        }
+        if self.ladder.factors.is_empty() || !self.ladder.factors.iter().all(|f| *f > 0.0 && f.is_finite()) {
+            return Err(Error::InvalidParams("ladder"));
+        }
+        if let Some(count) = self.count {
+            if count.min == 0 || count.min > count.max {
+                return Err(Error::InvalidParams("count"));
+            }
+        }
+        if let Some(edge) = self.edge.as_ref() {
+            edge.validate()?;
+        }
+        if let Some(srm) = self.srm.as_ref() {
+            srm.validate()?;
+        }
+        if let Some(protection) = self.protection.as_ref() {
+            protection.validate()?;
+        }
+        Ok(())
+    }
+}
+
+impl Default for VoteLadder {
+    fn default() -> Self {
+        Self::standard()
+    }
+}
+
+impl VoteLadder {
+    /// Factor of the default ladder; the presets are set relative to it
+    pub const STANDARD: f64 = 0.25;
+
+    /// The default. Every vote within a quarter of `deviation`, as segmentation has always done
+    pub fn standard() -> Self {
+        Self { factors: vec![Self::STANDARD] }
+    }
+
+    /// For natural images. Textures split into many clusters, so a cluster joins its closest
+    /// neighbour within twice the standard factor (half of `deviation`), and the rest within
+    /// the standard factor (a quarter of `deviation`)
+    pub fn photo() -> Self {
+        Self { factors: vec![Self::STANDARD * 2.0, Self::STANDARD] }
+    }
+
+    /// For flat-colored artwork. A cluster joins its closest neighbour within the standard
+    /// factor (a quarter of `deviation`), and the rest only within a quarter of that, so that
+    /// anti-aliased edges do not bridge two shapes
+    pub fn illustration() -> Self {
+        Self { factors: vec![Self::STANDARD, Self::STANDARD / 4.0] }
+    }
+
+    /// For a few flat colors with fine details. Half the factors of an illustration: an eighth
+    /// of `deviation` for the closest neighbour, a thirty-second for the rest
+    pub fn logo() -> Self {
+        Self { factors: vec![Self::STANDARD / 2.0, Self::STANDARD / 8.0] }
+    }
+
+    /// factor for the `rank`-th closest neighbour, from 0
+    fn factor(&self, rank: usize) -> f64 {
+        self.factors[std::cmp::min(rank, self.factors.len() - 1)]
+    }
+}
+
+impl Scribble {
+    pub fn new(label: u32, points: Vec<(u32, u32)>) -> Self {
+        Self { label, points }
+    }
+
+    /// the pixels set in `mask`
+    pub fn from_mask(label: u32, mask: &BinaryImage) -> Self {
+        let mut points = Vec::new();
+        for y in 0..mask.height {
+            for x in 0..mask.width {
+                if mask.get_pixel(x, y) {
+                    points.push((x as u32, y as u32));
+                }
+            }
+        }
+        Self::new(label, points)
+    }
+}
+
+impl RegionCount {
+    pub fn exact(count: usize) -> Self {
+        Self { min: count, max: count }
+    }
 }
 
 impl ProcessorTrait for Processor {
@@ -35,99 +295,949 @@ impl ProcessorTrait for Processor {
         Self::default()
     }
 
-    fn config(&mut self, params: Params) -> bool {
+    fn config(&mut self, params: Params) -> Result<(), Error> {
+        params.validate()?;
         self.params = params;
-        if self.clusters.is_some() {
-            let cluster = self.clusters.take().unwrap();
-            self.input(cluster);
+        if let Some(clusters) = self.clusters.take() {
+            self.input(clusters)?;
         }
-        true
+        Ok(())
     }
 
-    fn input(&mut self, input: Input) -> bool {
+    fn input(&mut self, input: Input) -> Result<(), Error> {
+        if input.output_len() == 0 {
+            return Err(Error::EmptyInput);
+        }
+        if self.params.scribbles.iter().flat_map(|scribble| scribble.points.iter())
+            .any(|&(x, y)| x >= input.width || y >= input.height) {
+            return Err(Error::InvalidParams("scribbles"));
+        }
+        let pixels = stats::source_pixels(self.params.source.as_ref(), &input.view())?;
+        self.edges = self.params.edge.as_ref().map(|edge| Edges::new(edge, pixels, input.width, input.height));
+        self.importance.clear();
+        if let Some(protection) = self.params.protection.as_ref() {
+            protection.check_size(input.width, input.height)?;
+            let view = input.view();
+            self.importance = vec![0.0; view.clusters.len()];
+            for index in view.clusters_output.iter() {
+                let cluster = view.get_cluster(*index);
+                self.importance[index.0 as usize] = protection.map.sum(&cluster.indices) / cluster.area() as f64;
+            }
+        }
         self.clusters = Some(input);
         let view = self.clusters.as_ref().unwrap().view();
-        let len = view.clusters_output.len();
-        self.counter = if len > 0 { len - 1 } else { 0 };
+        self.counter = view.clusters_output.len() - 1;
+        self.finished = false;
         self.forests = Forests::new();
         for index in view.clusters_output.iter() {
             self.forests.make_set(*index);
         }
-        len > 0
+        self.merger = None;
+        self.history.clear();
+        self.apply_scribbles();
+        Ok(())
     }
 
     fn tick(&mut self) -> bool {
-        let view = self.clusters.as_ref().unwrap().view();
-        let myselfi = view.clusters_output[self.counter];
-        let myself = view.get_cluster(myselfi);
-        let mut votes: Vec<(ClusterIndex, i32)> = myself.neighbours(&view).iter().map(|otheri| {
-            let other = view.get_cluster(*otheri);
-            (*otheri, Self::color_distance(myself, other))
-        }).collect();
-        votes.sort_by_key(|v| v.1);
-        for (i, v) in votes.iter().enumerate() {
-            let diff = v.1 as f64 / 10000.0;
-            if  i == 0 && diff > self.params.deviation ||
-                i == 1 && diff > self.params.deviation * 0.5 ||
-                diff > self.params.deviation * 0.25 {
-                break;
+        let view = match self.clusters.as_ref() {
+            Some(clusters) => clusters.view(),
+            None => return true,
+        };
+        if self.finished {
+            return true;
+        }
+        if let Some(count) = self.count() {
+            self.finished = self.tick_towards(count);
+            return self.finished;
+        }
+        // voting only reads the clusters, so a batch can be counted up front;
+        // the unions are then applied in the same order as one-by-one
+        let start = self.counter.saturating_sub(parallel::batch() - 1);
+        let batch = &view.clusters_output[start..=self.counter];
+        let measure = self.measure();
+        let ballots = parallel::map(batch, |myselfi| Self::votes(&view, *myselfi, &measure));
+        for (myselfi, votes) in batch.iter().zip(ballots.iter()).rev() {
+            // a vote counts only if all before it do, so it needs the largest deviation
+            // of them all; all are kept for the merge tree
+            let mut voting = true;
+            let mut threshold: f64 = 0.0;
+            for (i, v) in votes.iter().enumerate() {
+                let diff = v.1 as f64 / 10000.0;
+                let factor = self.params.ladder.factor(i);
+                voting = voting && diff <= self.params.deviation * factor;
+                if voting {
+                    Self::union(&mut self.forests, &mut self.set_seeds, myselfi, &v.0);
+                }
+                threshold = threshold.max(diff / factor);
+                self.history.push(Union {
+                    a: myselfi.0,
+                    b: v.0.0,
+                    distance: threshold,
+                });
             }
-            self.forests.union(&myselfi, &v.0);
         }
-        if self.counter > 0 {
-            self.counter -= 1;
+        if start > 0 {
+            self.counter = start - 1;
             false
         } else {
+            self.counter = 0;
+            self.finished = true;
             true
         }
     }
 
     fn progress(&self) -> u32 {
-        let total = self.clusters.as_ref().unwrap().output_len() - 1;
-        100 - 100 * self.counter as u32 / total as u32
+        let total = match self.clusters.as_ref() {
+            Some(clusters) => clusters.output_len() - 1,
+            None => return 0,
+        };
+        let scanned = if total == 0 {
+            100
+        } else {
+            100 - 100 * self.counter as u32 / total as u32
+        };
+        match (self.count(), self.merger.as_ref()) {
+            (None, _) => scanned,
+            (Some(_), None) => 0,
+            (Some(_), Some(merger)) if !merger.scanned => scanned / 2,
+            (Some(_), Some(merger)) if merger.finished => 100,
+            (Some(count), Some(merger)) => {
+                let merged = merger.initial - merger.nodes.len();
+                let needed = std::cmp::max(1, merger.initial.saturating_sub(count.min));
+                std::cmp::min(99, 50 + 50 * merged / needed) as u32
+            },
+        }
     }
 
-    /// to be called once only after process ends
-    fn output(&mut self) -> Output {
-        let mut aggregate = HashMap::new();
-        let mut pairs = Vec::new();
+    /// to be called after process ends
+    fn output(&mut self) -> Result<Output, Error> {
+        let sets = self.sets()?;
         let view = self.clusters.as_ref().unwrap().view();
-        for index in view.clusters_output.iter() {
-            let label = self.forests.find_set(&index).unwrap();
-            pairs.push((*index, label));
-            let cluster = view.get_cluster(*index);
-            (*aggregate.entry(label).or_insert_with(ColorSum::new)).add(&cluster.residue_color());
-        }
         let mut image = ColorImage::new_w_h(view.width as usize, view.height as usize);
-        for (index, label) in pairs.iter() {
-            let cluster = view.get_cluster(*index);
-            let color = aggregate.get(label).unwrap().average();
-            cluster.render_to_color_image_with_color(&view, &mut image, &color);
+        for (color, indices) in sets.iter() {
+            for index in indices.iter() {
+                view.get_cluster(*index).render_to_color_image_with_color(&view, &mut image, color);
+            }
         }
-        image
+        Ok(image)
     }
 
 }
 
 impl Processor {
-    fn color_distance(myself: &Cluster, other: &Cluster) -> i32 {
-        let mycolor = myself.residue_color();
-        let otcolor = other.residue_color();
-        (10000.0 * Self::color_diff_hsv(mycolor, otcolor)) as i32
+    /// capture the in-flight state
+    pub fn snapshot(&mut self) -> Result<Snapshot, Error> {
+        let clusters = self.clusters.as_ref().ok_or(Error::WrongStage("no input"))?;
+        let view = clusters.view();
+        let forests = &mut self.forests;
+        let labels = view.clusters_output.iter().map(|index| {
+            forests.find_set(index).unwrap()
+        }).collect();
+        Ok(Snapshot {
+            width: view.width,
+            height: view.height,
+            counter: self.counter,
+            finished: self.finished,
+            labels,
+            history: self.history.clone(),
+        })
+    }
+
+    /// resume from a snapshot; `input` must be the clusters originally given to this Processor
+    pub fn restore(&mut self, input: Input, snapshot: Snapshot) -> Result<(), Error> {
+        if  input.width != snapshot.width ||
+            input.height != snapshot.height ||
+            input.output_len() != snapshot.labels.len() ||
+            snapshot.counter >= snapshot.labels.len() {
+            return Err(Error::SnapshotMismatch);
+        }
+        let outputs: HashSet<u32> = input.view().clusters_output.iter().map(|index| index.0).collect();
+        if !snapshot.history.iter().all(|union| outputs.contains(&union.a) && outputs.contains(&union.b)) {
+            return Err(Error::InvalidSnapshot);
+        }
+        self.input(input)?;
+        let view = self.clusters.as_ref().unwrap().view();
+        let mut roots = HashMap::new();
+        for (index, label) in view.clusters_output.iter().zip(snapshot.labels.iter()) {
+            let root = roots.entry(*label).or_insert(*index);
+            self.forests.union(root, index);
+        }
+        self.counter = snapshot.counter;
+        self.history = snapshot.history;
+        if snapshot.finished {
+            self.finish()?;
+        } else if self.count().is_some() {
+            // region adjacency is not kept in snapshots; scan again over the restored sets
+            self.counter = snapshot.labels.len() - 1;
+        }
+        self.reseed();
+        Ok(())
+    }
+
+    /// the unions made so far as a dendrogram; to be called after process ends.
+    /// In voting mode the unions are replayed in order of distance rather than in the order
+    /// they were made, so where scribbles conflict, the union refused may differ from the run's
+    pub fn merge_tree(&self) -> Result<MergeTree, Error> {
+        let view = self.clusters.as_ref().ok_or(Error::WrongStage("no input"))?.view();
+        let leaf_of: HashMap<u32, usize> = view.clusters_output.iter().enumerate()
+            .map(|(leaf, index)| (index.0, leaf)).collect();
+        let mut unions = self.history.clone();
+        if self.count().is_none() {
+            // unions in voting mode do not depend on each other, only on the deviation
+            unions.sort_by(|x, y| x.distance.total_cmp(&y.distance));
+        }
+        let mut tree = MergeTree {
+            leaves: leaf_of.len(),
+            areas: view.clusters_output.iter().map(|index| view.get_cluster(*index).area()).collect(),
+            merges: Vec::new(),
+        };
+        let mut forests = Forests::new();
+        let mut nodes = HashMap::new();
+        let mut areas = tree.areas.clone();
+        let mut seeds: Vec<Option<u32>> = view.clusters_output.iter().map(|index| self.seeds.get(index).copied()).collect();
+        for leaf in 0..tree.leaves {
+            forests.make_set(leaf);
+            nodes.insert(forests.find_set(&leaf).unwrap(), leaf);
+        }
+        let mut height = 0.0_f64;
+        for union in unions.iter() {
+            let (x, y) = (leaf_of[&union.a], leaf_of[&union.b]);
+            let (a, b) = (nodes[&forests.find_set(&x).unwrap()], nodes[&forests.find_set(&y).unwrap()]);
+            if a == b || conflicts(seeds[a], seeds[b]) {
+                continue;
+            }
+            seeds.push(seeds[a].or(seeds[b]));
+            forests.union(&x, &y);
+            height = height.max(union.distance);
+            let node = tree.leaves + tree.merges.len();
+            let area = areas[a] + areas[b];
+            areas.push(area);
+            nodes.insert(forests.find_set(&x).unwrap(), node);
+            tree.merges.push(Merge { a, b, distance: height, area });
+        }
+        Ok(tree)
+    }
+
+    /// reset the sets to `tree` cut at `threshold`, after which the outputs reflect the cut;
+    /// ticking afterwards is not meaningful. `tree` must come from this Processor with the current input
+    pub fn cut(&mut self, tree: &MergeTree, threshold: f64) -> Result<(), Error> {
+        self.apply_cut(tree, |tree| tree.cut(threshold))
+    }
+
+    /// like [`Processor::cut`], but down to `count` sets
+    pub fn cut_count(&mut self, tree: &MergeTree, count: usize) -> Result<(), Error> {
+        self.apply_cut(tree, |tree| tree.cut_count(count))
     }
 
-    fn color_diff_hsv(a: Color, b: Color) -> f64 {
-        let a = a.to_hsv();
-        let b = b.to_hsv();
-        return 2.0 * wrap(a.h, b.h) + (a.s - b.s).abs() + (a.v - b.v).abs();
+    fn apply_cut<F>(&mut self, tree: &MergeTree, cut: F) -> Result<(), Error>
+    where
+        F: FnOnce(&MergeTree) -> Vec<usize>,
+    {
+        let clusters = self.clusters.take().ok_or(Error::WrongStage("no input"))?;
+        if clusters.output_len() != tree.leaves {
+            self.clusters = Some(clusters);
+            return Err(Error::InvalidParams("tree"));
+        }
+        let history = std::mem::take(&mut self.history);
+        self.input(clusters)?;
+        self.history = history;
+        let view = self.clusters.as_ref().unwrap().view();
+        let mut roots = HashMap::new();
+        for (index, label) in view.clusters_output.iter().zip(cut(tree).iter()) {
+            let root = roots.entry(*label).or_insert(*index);
+            self.forests.union(root, index);
+        }
+        self.finish()?;
+        self.reseed();
+        Ok(())
+    }
 
-        fn wrap(x: f64, y: f64) -> f64 {
-            let d = (x - y).abs();
-            if d < 0.5 {
-                d
+    /// stop at the sets as they are, with no more unions to make
+    fn finish(&mut self) -> Result<(), Error> {
+        self.counter = 0;
+        self.finished = true;
+        if self.count().is_some() {
+            let view = self.clusters.as_ref().unwrap().view();
+            let pixels = stats::source_pixels(self.params.source.as_ref(), &view)?;
+            let pixels = self.params.srm.map(|_| pixels);
+            let mut merger = Merger::new(&view, pixels, &self.importance, &mut self.forests, &self.seeds);
+            merger.scanned = true;
+            merger.finished = true;
+            self.merger = Some(Box::new(merger));
+        }
+        Ok(())
+    }
+
+    /// union the clusters under each scribble; to be called on input
+    fn apply_scribbles(&mut self) {
+        self.seeds.clear();
+        self.set_seeds.clear();
+        let view = self.clusters.as_ref().unwrap().view();
+        let mut firsts = HashMap::new();
+        for scribble in self.params.scribbles.iter() {
+            for &(x, y) in scribble.points.iter() {
+                let index = view.get_cluster_at(y * view.width + x);
+                if self.forests.find_set(&index).is_none() {
+                    continue;
+                }
+                // a cluster under conflicting scribbles keeps the first label
+                let label = *self.seeds.entry(index).or_insert(scribble.label);
+                let first = *firsts.entry(label).or_insert(index);
+                if first != index && Self::union(&mut self.forests, &mut self.set_seeds, &first, &index) {
+                    self.history.push(Union {
+                        a: first.0,
+                        b: index.0,
+                        distance: 0.0,
+                    });
+                }
+                let root = self.forests.find_set(&index).unwrap();
+                self.set_seeds.insert(root, label);
+            }
+        }
+    }
+
+    /// scribble labels of the sets, after the sets were rebuilt
+    fn reseed(&mut self) {
+        self.set_seeds.clear();
+        for (index, label) in self.seeds.iter() {
+            self.set_seeds.insert(self.forests.find_set(index).unwrap(), *label);
+        }
+    }
+
+    /// union the sets of two clusters, unless they are scribbled with different labels;
+    /// returns true if they were separate and are now one
+    fn union(forests: &mut Forests<ClusterIndex>, set_seeds: &mut HashMap<Label, u32>, a: &ClusterIndex, b: &ClusterIndex) -> bool {
+        let (x, y) = (forests.find_set(a).unwrap(), forests.find_set(b).unwrap());
+        if x == y {
+            return false;
+        }
+        let (sx, sy) = (set_seeds.get(&x).copied(), set_seeds.get(&y).copied());
+        if conflicts(sx, sy) {
+            return false;
+        }
+        forests.union(a, b);
+        set_seeds.remove(&x);
+        set_seeds.remove(&y);
+        if let Some(seed) = sx.or(sy) {
+            set_seeds.insert(forests.find_set(a).unwrap(), seed);
+        }
+        true
+    }
+
+    /// [`RegionCount`] to merge towards, if not voting
+    fn count(&self) -> Option<RegionCount> {
+        match (self.params.count, self.params.srm) {
+            (Some(count), _) => Some(count),
+            (None, Some(_)) => Some(RegionCount { min: 1, max: usize::MAX }),
+            (None, None) => None,
+        }
+    }
+
+    fn measure(&self) -> Measure<'_> {
+        Measure {
+            metric: &*self.params.metric,
+            edges: self.edges.as_ref(),
+            srm: self.params.srm.as_ref(),
+            area: self.clusters.as_ref().map_or(0, |clusters| (clusters.width * clusters.height) as usize),
+            protection: self.params.protection.as_ref(),
+            importance: &self.importance,
+        }
+    }
+
+    /// tick in [`RegionCount`] mode: scan the adjacency of sets in batches, then merge
+    fn tick_towards(&mut self, count: RegionCount) -> bool {
+        let view = self.clusters.as_ref().unwrap().view();
+        if self.merger.is_none() {
+            // the source was checked on input
+            let pixels = stats::source_pixels(self.params.source.as_ref(), &view).unwrap();
+            let pixels = self.params.srm.map(|_| pixels);
+            self.merger = Some(Box::new(Merger::new(&view, pixels, &self.importance, &mut self.forests, &self.seeds)));
+        }
+        let merger = self.merger.as_mut().unwrap();
+        let measure = Measure {
+            metric: &*self.params.metric,
+            edges: self.edges.as_ref(),
+            srm: self.params.srm.as_ref(),
+            area: (view.width * view.height) as usize,
+            protection: self.params.protection.as_ref(),
+            importance: &self.importance,
+        };
+        if !merger.scanned {
+            let start = self.counter.saturating_sub(parallel::batch() - 1);
+            let batch = &view.clusters_output[start..=self.counter];
+            let neighbours = parallel::map(batch, |myselfi| Self::neighbours(&view, *myselfi, measure.edges));
+            for (myselfi, others) in batch.iter().zip(neighbours.iter()) {
+                merger.connect(&mut self.forests, myselfi, others);
+            }
+            if start > 0 {
+                self.counter = start - 1;
             } else {
-                1.0 - d
+                self.counter = 0;
+                merger.start(&measure);
+            }
+            return false;
+        }
+        for _ in 0..MERGES_PER_TICK {
+            if merger.merge_next(&mut self.forests, &mut self.history, &measure, count, self.params.deviation) {
+                return true;
+            }
+        }
+        false
+    }
+
+    /// per-pixel set labels with the table of sets; to be called after process ends
+    pub fn output_regions(&mut self) -> Result<RegionMap, Error> {
+        let sets = self.sets()?;
+        let view = self.clusters.as_ref().unwrap().view();
+        Ok(RegionMap::from_groups(view.width, view.height, sets.iter().map(|(color, indices)| {
+            let pixels = indices.iter().flat_map(|index| view.get_cluster(*index).indices.iter().copied());
+            (*color, pixels)
+        })))
+    }
+
+    /// adjacency graph of the sets, whose ids match [`Processor::output_regions`];
+    /// to be called after process ends
+    pub fn output_graph(&mut self) -> Result<RegionGraph, Error> {
+        let map = self.output_regions()?;
+        let metric = &*self.params.metric;
+        Ok(RegionGraph::from_map(map, |a, b| metric.distance(a, b)))
+    }
+
+    /// output clusters grouped by set, with the average color of each set;
+    /// sets are ordered by their first cluster in output order
+    fn sets(&mut self) -> Result<Vec<(Color, Vec<ClusterIndex>)>, Error> {
+        let view = self.clusters.as_ref().ok_or(Error::WrongStage("no input"))?.view();
+        let mut positions = HashMap::new();
+        let mut sets: Vec<(ColorSum, Vec<ClusterIndex>)> = Vec::new();
+        for index in view.clusters_output.iter() {
+            let label = self.forests.find_set(index).unwrap();
+            let position = *positions.entry(label).or_insert_with(|| {
+                sets.push((ColorSum::new(), Vec::new()));
+                sets.len() - 1
+            });
+            sets[position].0.add(&view.get_cluster(*index).residue_color());
+            sets[position].1.push(*index);
+        }
+        Ok(sets.into_iter().map(|(sum, indices)| (sum.average(), indices)).collect())
+    }
+
+    /// neighbours of a cluster sorted by distance, leaving out those it may not merge with
+    fn votes(view: &ClustersView, myselfi: ClusterIndex, measure: &Measure) -> Vec<(ClusterIndex, i32)> {
+        let color = view.get_cluster(myselfi).residue_color();
+        let neighbours = Self::neighbours(view, myselfi, measure.edges);
+        let mut votes: Vec<(ClusterIndex, i32)> = neighbours.iter().filter_map(|(otheri, boundary)| {
+            let other = view.get_cluster(*otheri);
+            let importance = measure.importance_of(myselfi).max(measure.importance_of(*otheri));
+            Some((*otheri, measure.between(color, other.residue_color(), boundary, importance)?))
+        }).collect();
+        votes.sort_by_key(|v| v.1);
+        votes
+    }
+
+    /// neighbours of a cluster, with the strength of the boundary if measuring edges
+    fn neighbours(view: &ClustersView, myselfi: ClusterIndex, edges: Option<&Edges>) -> Vec<(ClusterIndex, BoundaryStrength)> {
+        let myself = view.get_cluster(myselfi);
+        match edges {
+            Some(edges) => edges.boundaries(&myself.indices, view.cluster_indices, |index| {
+                index != ClusterIndex(0) && index != myselfi
+            }),
+            None => myself.neighbours(view).into_iter().map(|index| (index, BoundaryStrength::default())).collect(),
+        }
+    }
+}
+
+impl Measure<'_> {
+    /// `None` if the merge is refused; `importance` is that of the more important side
+    fn between(&self, a: Color, b: Color, boundary: &BoundaryStrength, importance: f64) -> Option<i32> {
+        let mut penalty = match self.edges {
+            Some(edges) => edges.penalty(boundary)?,
+            None => 0.0,
+        };
+        if let Some(protection) = self.protection {
+            if protection.refuses(importance) {
+                return None;
+            }
+            penalty += protection.penalty(importance);
+        }
+        Some((10000.0 * (self.metric.distance(a, b) + penalty)) as i32)
+    }
+
+    fn importance_of(&self, index: ClusterIndex) -> f64 {
+        self.importance.get(index.0 as usize).copied().unwrap_or(0.0)
+    }
+}
+
+impl Merger {
+    /// one region per set of `forests`, measuring the stats of `pixels` if given
+    fn new(
+        view: &ClustersView, pixels: Option<&[u8]>, importance: &[f64],
+        forests: &mut Forests<ClusterIndex>, seeds: &HashMap<ClusterIndex, u32>,
+    ) -> Self {
+        let mut keys = HashMap::new();
+        for index in view.clusters_output.iter() {
+            let key = keys.entry(forests.find_set(index).unwrap()).or_insert(index.0);
+            *key = std::cmp::min(*key, index.0);
+        }
+        let mut nodes = HashMap::new();
+        for index in view.clusters_output.iter() {
+            let key = keys[&forests.find_set(index).unwrap()];
+            let node = nodes.entry(key).or_insert_with(|| Node {
+                sum: ColorSum::new(),
+                area: 0,
+                stats: pixels.map(|_| RegionStats::new()),
+                importance: 0.0,
+                neighbours: HashMap::new(),
+                seed: None,
+            });
+            node.sum.add(&view.get_cluster(*index).residue_color());
+            node.area += view.get_cluster(*index).area() as u64;
+            if let (Some(stats), Some(pixels)) = (node.stats.as_mut(), pixels) {
+                stats.merge(&RegionStats::from_pixels(pixels, &view.get_cluster(*index).indices));
+            }
+            if let Some(mean) = importance.get(index.0 as usize) {
+                node.importance += mean * view.get_cluster(*index).area() as f64;
+            }
+            node.seed = node.seed.or_else(|| seeds.get(index).copied());
+        }
+        Self {
+            initial: nodes.len(),
+            nodes,
+            keys,
+            queue: BinaryHeap::new(),
+            scanned: false,
+            finished: false,
+        }
+    }
+
+    /// record that a cluster borders on `others`
+    fn connect(&mut self, forests: &mut Forests<ClusterIndex>, myselfi: &ClusterIndex, others: &[(ClusterIndex, BoundaryStrength)]) {
+        let mykey = self.keys[&forests.find_set(myselfi).unwrap()];
+        for (otheri, boundary) in others.iter() {
+            let otherkey = self.keys[&forests.find_set(otheri).unwrap()];
+            if mykey != otherkey {
+                self.nodes.get_mut(&mykey).unwrap().neighbours.entry(otherkey).or_default().merge(boundary);
+                self.nodes.get_mut(&otherkey).unwrap().neighbours.entry(mykey).or_default().merge(boundary);
+            }
+        }
+    }
+
+    /// queue every adjacent pair once scanning is done
+    fn start(&mut self, measure: &Measure) {
+        let mut pairs = Vec::new();
+        for (&key, node) in self.nodes.iter() {
+            pairs.extend(node.neighbours.keys().filter(|&&other| key < other).map(|&other| (key, other)));
+        }
+        for (a, b) in pairs {
+            self.push(measure, a, b);
+        }
+        self.keys.clear();
+        self.scanned = true;
+    }
+
+    /// merge the closest pair if allowed; returns true when no more merges are allowed
+    fn merge_next(
+        &mut self, forests: &mut Forests<ClusterIndex>, history: &mut Vec<Union>,
+        measure: &Measure, count: RegionCount, deviation: f64,
+    ) -> bool {
+        while let Some(&Reverse((distance, a, b))) = self.queue.peek() {
+            if !self.is_current(measure, distance, a, b) || conflicts(self.nodes[&a].seed, self.nodes[&b].seed) {
+                self.queue.pop();
+                continue;
+            }
+            let len = self.nodes.len();
+            if len <= count.min {
+                break;
+            }
+            if len <= count.max {
+                match measure.srm {
+                    // the test does not follow the order of distance, so keep looking
+                    Some(srm) if !srm.test(self.nodes[&a].stats(), self.nodes[&b].stats(), measure.area) => {
+                        self.queue.pop();
+                        continue;
+                    },
+                    None if distance as f64 / 10000.0 > deviation => break,
+                    _ => (),
+                }
+            }
+            self.queue.pop();
+            self.merge(forests, measure, a, b);
+            history.push(Union {
+                a,
+                b,
+                distance: distance as f64 / 10000.0,
+            });
+            return false;
+        }
+        self.finished = true;
+        true
+    }
+
+    /// merge region `b` into `a`, where `a < b`
+    fn merge(&mut self, forests: &mut Forests<ClusterIndex>, measure: &Measure, a: u32, b: u32) {
+        forests.union(&ClusterIndex(a), &ClusterIndex(b));
+        let absorbed = self.nodes.remove(&b).unwrap();
+        for (other, boundary) in absorbed.neighbours.iter() {
+            if *other != a {
+                let node = self.nodes.get_mut(other).unwrap();
+                node.neighbours.remove(&b);
+                node.neighbours.entry(a).or_default().merge(boundary);
+            }
+        }
+        let node = self.nodes.get_mut(&a).unwrap();
+        node.neighbours.remove(&b);
+        for (other, boundary) in absorbed.neighbours.into_iter().filter(|(other, _)| *other != a) {
+            node.neighbours.entry(other).or_default().merge(&boundary);
+        }
+        node.sum.merge(&absorbed.sum);
+        node.area += absorbed.area;
+        if let (Some(stats), Some(absorbed)) = (node.stats.as_mut(), absorbed.stats.as_ref()) {
+            stats.merge(absorbed);
+        }
+        node.importance += absorbed.importance;
+        node.seed = node.seed.or(absorbed.seed);
+        let neighbours: Vec<u32> = node.neighbours.keys().copied().collect();
+        for other in neighbours {
+            self.push(measure, std::cmp::min(a, other), std::cmp::max(a, other));
+        }
+    }
+
+    /// queue a pair unless it may not merge
+    fn push(&mut self, measure: &Measure, a: u32, b: u32) {
+        if let Some(distance) = self.distance(measure, a, b) {
+            self.queue.push(Reverse((distance, a, b)));
+        }
+    }
+
+    /// whether a queued pair still exists with the same distance
+    fn is_current(&self, measure: &Measure, distance: i32, a: u32, b: u32) -> bool {
+        match (self.nodes.get(&a), self.nodes.contains_key(&b)) {
+            (Some(node), true) => node.neighbours.contains_key(&b) && self.distance(measure, a, b) == Some(distance),
+            _ => false,
+        }
+    }
+
+    fn distance(&self, measure: &Measure, a: u32, b: u32) -> Option<i32> {
+        let (x, y) = (&self.nodes[&a], &self.nodes[&b]);
+        let importance = x.importance().max(y.importance());
+        measure.between(x.sum.average(), y.sum.average(), &x.neighbours[&b], importance)
+    }
+}
+
+impl Node {
+    /// mean over the pixels
+    fn importance(&self) -> f64 {
+        self.importance / std::cmp::max(self.area, 1) as f64
+    }
+
+    /// kept by every region when testing with `srm`
+    fn stats(&self) -> &RegionStats {
+        self.stats.as_ref().unwrap()
+    }
+}
+
+impl MergeTree {
+    /// set of each leaf when merging up to and including `threshold`;
+    /// sets are numbered in order of their first leaf
+    pub fn cut(&self, threshold: f64) -> Vec<usize> {
+        self.cut_after(self.merges.iter().take_while(|merge| merge.distance <= threshold).count())
+    }
+
+    /// set of each leaf when merging down to `count` sets, or as far as the tree goes
+    pub fn cut_count(&self, count: usize) -> Vec<usize> {
+        self.cut_after(std::cmp::min(self.leaves.saturating_sub(count), self.merges.len()))
+    }
+
+    /// number of sets when merging up to and including `threshold`
+    pub fn count_at(&self, threshold: f64) -> usize {
+        self.leaves - self.merges.iter().take_while(|merge| merge.distance <= threshold).count()
+    }
+
+    fn cut_after(&self, merges: usize) -> Vec<usize> {
+        let mut forests = Forests::new();
+        for leaf in 0..self.leaves {
+            forests.make_set(leaf);
+        }
+        // a leaf under every node, to union by
+        let mut leaf_of: Vec<usize> = (0..self.leaves).collect();
+        for merge in self.merges[..merges].iter() {
+            let (a, b) = (leaf_of[merge.a], leaf_of[merge.b]);
+            forests.union(&a, &b);
+            leaf_of.push(a);
+        }
+        let mut sets = HashMap::new();
+        (0..self.leaves).map(|leaf| {
+            let label = forests.find_set(&leaf).unwrap();
+            let next = sets.len();
+            *sets.entry(label).or_insert(next)
+        }).collect()
+    }
+}
+
+/// whether two sets are scribbled with different labels
+fn conflicts(a: Option<u32>, b: Option<u32>) -> bool {
+    matches!((a, b), (Some(a), Some(b)) if a != b)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use visioncortex::BoundingRect;
+    use crate::region::Edge;
+    use crate::testing::{self, persist};
+
+    fn input() -> Input {
+        testing::clusters(testing::image(256, 256, 1))
+    }
+
+    #[test]
+    fn restore_resumes_identically() {
+        for count in [None, Some(RegionCount::exact(8))] {
+            // deviation high enough for voting to merge as well
+            let params = || Params {
+                deviation: 0.6,
+                count,
+                ..Default::default()
+            };
+            let mut uninterrupted = Processor::new();
+            uninterrupted.config(params()).unwrap();
+            uninterrupted.input(input()).unwrap();
+            let mut ticks = 0;
+            while !uninterrupted.tick() {
+                ticks += 1;
+            }
+            assert!(ticks > 2, "{:?}", count);
+            assert!(!uninterrupted.merge_tree().unwrap().merges.is_empty(), "{:?}", count);
+
+            let mut interrupted = Processor::new();
+            interrupted.config(params()).unwrap();
+            interrupted.input(input()).unwrap();
+            for _ in 0..ticks / 2 {
+                interrupted.tick();
             }
+            let mut resumed = Processor::new();
+            resumed.config(params()).unwrap();
+            resumed.restore(input(), persist(interrupted.snapshot().unwrap())).unwrap();
+            while !resumed.tick() {}
+            assert_eq!(resumed.output().unwrap().pixels, uninterrupted.output().unwrap().pixels, "{:?}", count);
+        }
+    }
+
+    #[test]
+    fn restore_finished_adds_nothing() {
+        for count in [None, Some(RegionCount::exact(8))] {
+            let mut processor = Processor::new();
+            processor.config(Params { deviation: 0.6, count, ..Default::default() }).unwrap();
+            processor.input(input()).unwrap();
+            while !processor.tick() {}
+            let snapshot = persist(processor.snapshot().unwrap());
+            let merges = processor.merge_tree().unwrap().merges.len();
+
+            let mut resumed = Processor::new();
+            resumed.config(Params { deviation: 0.6, count, ..Default::default() }).unwrap();
+            resumed.restore(input(), snapshot).unwrap();
+            assert_eq!(resumed.progress(), 100, "{:?}", count);
+            assert!(resumed.tick(), "{:?}", count);
+            assert_eq!(resumed.history.len(), processor.history.len(), "{:?}", count);
+            assert_eq!(resumed.merge_tree().unwrap().merges.len(), merges, "{:?}", count);
+            assert_eq!(resumed.output().unwrap().pixels, processor.output().unwrap().pixels, "{:?}", count);
+        }
+    }
+
+    #[test]
+    fn restore_rejects_foreign_history() {
+        let mut processor = Processor::new();
+        processor.config(Params { deviation: 0.6, ..Default::default() }).unwrap();
+        processor.input(input()).unwrap();
+        processor.tick();
+        let mut snapshot = processor.snapshot().unwrap();
+        snapshot.history.push(Union { a: u32::MAX, b: 0, distance: 0.0 });
+        assert_eq!(Processor::new().restore(input(), snapshot).err(), Some(Error::InvalidSnapshot));
+    }
+
+    /// output of a fresh run with `params`
+    fn run(params: Params) -> Output {
+        let mut processor = Processor::new();
+        processor.config(params).unwrap();
+        processor.input(input()).unwrap();
+        while !processor.tick() {}
+        processor.output().unwrap()
+    }
+
+    #[test]
+    fn cut_equals_voting_at_deviation() {
+        let mut processor = Processor::new();
+        processor.config(Params { deviation: 0.6, ..Default::default() }).unwrap();
+        processor.input(input()).unwrap();
+        while !processor.tick() {}
+        let tree = processor.merge_tree().unwrap();
+        for deviation in [0.0, 0.15, 0.3, 0.6] {
+            processor.cut(&tree, deviation).unwrap();
+            let expected = run(Params { deviation, ..Default::default() });
+            assert_eq!(processor.output().unwrap().pixels, expected.pixels, "{}", deviation);
+        }
+        assert!(tree.count_at(0.15) > tree.count_at(0.6));
+    }
+
+    #[test]
+    fn cut_count_equals_merging_to_count() {
+        let mut processor = Processor::new();
+        processor.config(Params { count: Some(RegionCount::exact(4)), ..Default::default() }).unwrap();
+        processor.input(input()).unwrap();
+        while !processor.tick() {}
+        let tree = processor.merge_tree().unwrap();
+        for count in [4, 8, 32] {
+            processor.cut_count(&tree, count).unwrap();
+            let expected = run(Params { count: Some(RegionCount::exact(count)), ..Default::default() });
+            assert_eq!(processor.output().unwrap().pixels, expected.pixels, "{}", count);
         }
     }
-}
\ No newline at end of file
+
+
+    const RED: [u8; 3] = [200, 0, 0];
+    const DARK_RED: [u8; 3] = [190, 0, 0];
+    const BLUE: [u8; 3] = [0, 0, 200];
+    const DARK_BLUE: [u8; 3] = [0, 0, 190];
+
+    /// region of each block of an image of [`testing::blocks`], row by row, once segmented with `params`
+    fn segment_blocks(params: Params, colors: &[&[[u8; 3]]]) -> Vec<u32> {
+        let mut processor = Processor::new();
+        processor.config(params).unwrap();
+        processor.input(testing::block_clusters(testing::blocks(colors))).unwrap();
+        while !processor.tick() {}
+        let map = processor.output_regions().unwrap();
+        colors.iter().enumerate().flat_map(|(row, blocks)| {
+            (0..blocks.len()).map(move |column| (column * 8 + 4, row * 8 + 4))
+        }).map(|(x, y)| map.labels.get_pixel(x, y)).collect()
+    }
+
+    /// scribble over the block in `column` of the top row of blocks
+    fn scribble(label: u32, column: u32) -> Scribble {
+        Scribble::new(label, vec![(column * 8 + 4, 4)])
+    }
+
+    #[test]
+    fn scribbles_link_and_separate() {
+        let strip: &[&[[u8; 3]]] = &[&[RED, DARK_RED, BLUE, DARK_BLUE]];
+        for count in [None, Some(RegionCount::exact(2))] {
+            // similar neighbours join
+            let regions = segment_blocks(Params { deviation: 0.6, count, ..Default::default() }, strip);
+            assert_eq!(regions[0], regions[1], "{:?}", count);
+            assert_eq!(regions[2], regions[3], "{:?}", count);
+            assert_ne!(regions[1], regions[2], "{:?}", count);
+
+            // unless scribbled with different labels
+            let regions = segment_blocks(Params {
+                deviation: 0.6,
+                count,
+                scribbles: vec![scribble(1, 0), scribble(2, 1)],
+                ..Default::default()
+            }, strip);
+            assert_ne!(regions[0], regions[1], "{:?}", count);
+        }
+        for count in [None, Some(RegionCount::exact(3))] {
+            // blocks apart and of different colors join if scribbled with the same label,
+            // while the rest stay as they are
+            let regions = segment_blocks(Params {
+                count,
+                scribbles: vec![scribble(1, 0), scribble(1, 3)],
+                ..Default::default()
+            }, strip);
+            assert_eq!(regions[0], regions[3], "{:?}", count);
+            assert_ne!(regions[0], regions[1], "{:?}", count);
+            assert_ne!(regions[0], regions[2], "{:?}", count);
+            assert_ne!(regions[1], regions[2], "{:?}", count);
+        }
+    }
+
+    #[test]
+    fn strong_edges_refuse_merges() {
+        let strip: &[&[[u8; 3]]] = &[&[RED, DARK_RED]];
+        // a sharp edge between the blocks, unlike in the input
+        let source = || Some(testing::blocks(&[&[[0, 0, 0], [255, 255, 255]]]));
+        let edge = Some(EdgeParams { weight: 0.0, threshold: 0.5 });
+        for count in [None, Some(RegionCount::exact(1))] {
+            let params = || Params { deviation: 0.6, count, ..Default::default() };
+            let regions = segment_blocks(Params { source: source(), ..params() }, strip);
+            assert_eq!(regions[0], regions[1], "{:?}", count);
+            let regions = segment_blocks(Params { edge, ..params() }, strip);
+            assert_eq!(regions[0], regions[1], "{:?}", count);
+            let regions = segment_blocks(Params { edge, source: source(), ..params() }, strip);
+            assert_ne!(regions[0], regions[1], "{:?}", count);
+        }
+    }
+
+    #[test]
+    fn region_count_within_range() {
+        const GREEN: [u8; 3] = [0, 200, 0];
+        const DARK_GREEN: [u8; 3] = [0, 190, 0];
+        let strip: &[&[[u8; 3]]] = &[&[RED, DARK_RED, BLUE, DARK_BLUE, GREEN, DARK_GREEN]];
+        let count = |deviation, min, max| {
+            let regions = segment_blocks(Params {
+                deviation,
+                count: Some(RegionCount { min, max }),
+                ..Default::default()
+            }, strip);
+            regions.iter().collect::<HashSet<_>>().len()
+        };
+        // down to `max` whatever the color difference
+        assert_eq!(count(0.0, 2, 4), 4);
+        assert_eq!(count(0.0, 5, 5), 5);
+        assert_eq!(count(0.0, 1, 1), 1);
+        // then on to `min` for as long as within `deviation`: the pairs of similar blocks
+        assert_eq!(count(0.6, 2, 4), 3);
+        assert_eq!(count(100.0, 2, 4), 2);
+        // none is merged if there are no more than `max` already
+        assert_eq!(count(0.0, 2, 8), 6);
+    }
+
+    #[test]
+    fn output_regions_of_blocks() {
+        const GREEN: [u8; 3] = [0, 200, 0];
+        // the red blocks touch only at a corner
+        let colors: &[&[[u8; 3]]] = &[&[RED, BLUE], &[GREEN, RED]];
+        let mut processor = Processor::new();
+        processor.input(testing::block_clusters(testing::blocks(colors))).unwrap();
+        while !processor.tick() {}
+        testing::assert_block_regions(&processor.output_regions().unwrap(), colors);
+
+        let mut processor = Processor::new();
+        processor.config(Params { deviation: 0.6, ..Default::default() }).unwrap();
+        processor.input(testing::block_clusters(testing::blocks(&[&[RED, DARK_RED]]))).unwrap();
+        while !processor.tick() {}
+        let map = processor.output_regions().unwrap();
+        assert!(map.labels.pixels.iter().all(|label| *label == 0));
+        assert_eq!(map.regions.len(), 1);
+        let region = &map.regions[0];
+        assert_eq!((region.area, region.rect), (128, BoundingRect::new_x_y_w_h(0, 0, 16, 8)));
+        assert_eq!((region.color.r, region.color.g, region.color.b), (195, 0, 0));
+    }
+
+    #[test]
+    fn output_graph_of_blocks() {
+        const GREEN: [u8; 3] = [0, 200, 0];
+        // the red blocks touch only at a corner, as do the blue and the green
+        let colors: &[&[[u8; 3]]] = &[&[RED, BLUE], &[GREEN, RED]];
+        let mut processor = Processor::new();
+        processor.input(testing::block_clusters(testing::blocks(colors))).unwrap();
+        while !processor.tick() {}
+        let map = processor.output_regions().unwrap();
+        let graph = processor.output_graph().unwrap();
+        assert_eq!(graph.nodes, map.regions);
+
+        let region = |block: usize| map.labels.get_pixel(block % 2 * 8 + 4, block / 2 * 8 + 4);
+        let color = |block: usize| {
+            let [r, g, b] = colors[block / 2][block % 2];
+            Color::new(r, g, b)
+        };
+        let metric = Params::default().metric;
+        // blocks sharing a side, in each row then in each column
+        let mut expected: Vec<Edge> = [(0, 1), (2, 3), (0, 2), (1, 3)].iter().map(|&(x, y)| {
+            let (x_id, y_id) = (region(x), region(y));
+            let (a, b) = (x_id.min(y_id), x_id.max(y_id));
+            Edge { a, b, boundary: 8, color_distance: metric.distance(color(x), color(y)) }
+        }).collect();
+        expected.sort_by_key(|edge| (edge.a, edge.b));
+        assert_eq!(graph.edges, expected);
+        assert_eq!(graph.edges_of(region(0)).count(), 2);
+    }
+}
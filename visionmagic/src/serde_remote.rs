@@ -0,0 +1,32 @@
+//! Serde definitions for types we store in snapshots that serde cannot derive for, such as
+//! visioncortex's
+use serde::{Deserialize, Serialize};
+use visioncortex::Color;
+
+#[derive(Serialize, Deserialize)]
+#[serde(remote = "Color")]
+pub(crate) struct ColorDef {
+    r: u8,
+    g: u8,
+    b: u8,
+    a: u8,
+}
+
+/// [`RegionStats::histogram`](crate::stats::RegionStats::histogram), longer than the arrays serde
+/// supports
+pub(crate) mod histogram {
+    use std::convert::TryInto;
+    use serde::de::Error;
+    use serde::{Deserialize, Deserializer, Serializer};
+    use crate::stats::HISTOGRAM_LEN;
+
+    pub(crate) fn serialize<S: Serializer>(histogram: &[u32; HISTOGRAM_LEN], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(histogram.iter())
+    }
+
+    pub(crate) fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<[u32; HISTOGRAM_LEN], D::Error> {
+        let bins = Vec::<u32>::deserialize(deserializer)?;
+        let len = bins.len();
+        bins.try_into().map_err(|_| D::Error::invalid_length(len, &"one count per histogram bin"))
+    }
+}
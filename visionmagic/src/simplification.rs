@@ -1,6 +1,10 @@
 //! Processor to simplify an image by pruning the image tree
-use visioncortex::{Color, CompoundPath, PathSimplifyMode};
+use std::fmt::Write;
+use visioncortex::{Color, CompoundPath, CompoundPathElement, PathSimplifyMode};
 use visioncortex::color_clusters::{Cluster, Clusters, ClustersView};
+use crate::Error;
+use crate::curve_fit;
+use crate::parallel;
 use crate::pipeline::Processor as ProcessorTrait;
 
 #[derive(Default)]
@@ -28,11 +32,36 @@ pub struct Params {
     pub fidelity: u32,
     /// Valid range is 0~65535. Ratio of how many points we use to outline each shape 
     pub shape_details: u32,
+    /// How each shape is outlined
+    pub path_mode: PathMode,
+}
+
+/// Geometry of the output paths
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PathMode {
+    /// Dense polylines
+    #[default]
+    Polygon,
+    /// Cubic Bezier curves fitted over the polylines; far more compact.
+    /// Outlines with more corners than curves remain polygons
+    Spline,
 }
 
 impl Params {
     pub const MAX_FIDELITY: u32 = 65535;
     pub const MAX_SHAPE_DETAILS: u32 = 65535;
+    /// Maximum distance (in pixels) between a fitted curve and the polygon it replaces
+    const CURVE_TOLERANCE: f64 = 0.5;
+
+    fn validate(&self) -> Result<(), Error> {
+        if self.fidelity > Self::MAX_FIDELITY {
+            return Err(Error::InvalidParams("fidelity"));
+        }
+        if self.shape_details > Self::MAX_SHAPE_DETAILS {
+            return Err(Error::InvalidParams("shape_details"));
+        }
+        Ok(())
+    }
 }
 
 impl Default for Params {
@@ -41,6 +70,7 @@ impl Default for Params {
         Self {
             fidelity: Self::MAX_FIDELITY,
             shape_details: Self::MAX_SHAPE_DETAILS,
+            path_mode: PathMode::Polygon,
         }
     }
 }
@@ -56,41 +86,53 @@ impl ProcessorTrait for Processor {
     }
 
     /// configure simplification parameters; can be reconfigured on runtime
-    fn config(&mut self, params: Params) -> bool {
+    fn config(&mut self, params: Params) -> Result<(), Error> {
+        params.validate()?;
         self.params = params;
-        if self.clusters.is_some() {
-            self.output();
-            let clusters = self.clusters.take().unwrap();
-            self.input(clusters);
+        if let Some(clusters) = self.clusters.take() {
+            self.buffer.clear();
+            self.input(clusters)?;
         }
-        true
+        Ok(())
     }
 
-    fn input(&mut self, input: Input) -> bool {
+    fn input(&mut self, input: Input) -> Result<(), Error> {
+        let len = input.output_len();
+        if len == 0 {
+            return Err(Error::EmptyInput);
+        }
         self.clusters = Some(input);
-        let len = self.clusters.as_ref().unwrap().output_len();
-        self.counter = if len > 0 { len - 1 } else { 0 };
+        self.counter = len - 1;
         let fraction = self.params.fidelity as f64 / Params::MAX_FIDELITY as f64;
-        self.stop = self.counter as usize - (self.counter as f64 * fraction.powi(3)) as usize;
-        len > 0
+        self.stop = self.counter - (self.counter as f64 * fraction.powi(3)) as usize;
+        Ok(())
     }
 
     fn tick(&mut self) -> bool {
-        let view = self.clusters.as_ref().unwrap().view();
-        let cluster = view.get_cluster(view.clusters_output[self.counter]);
-        if let Some(output) = self.process_cluster(&view, cluster) {
-            self.buffer.push(output);
-        }
-        if self.counter > self.stop {
-            self.counter -= 1;
+        let view = match self.clusters.as_ref() {
+            Some(clusters) => clusters.view(),
+            None => return true,
+        };
+        let start = std::cmp::max(self.stop, self.counter.saturating_sub(parallel::batch() - 1));
+        let batch = &view.clusters_output[start..=self.counter];
+        let outputs = parallel::map(batch, |index| {
+            self.process_cluster(&view, view.get_cluster(*index))
+        });
+        self.buffer.extend(outputs.into_iter().rev().flatten());
+        if start > self.stop {
+            self.counter = start - 1;
             false
         } else {
+            self.counter = start;
             true
         }
     }
 
     fn progress(&self) -> u32 {
-        let total = self.clusters.as_ref().unwrap().output_len() - 1;
+        let total = match self.clusters.as_ref() {
+            Some(clusters) => clusters.output_len() - 1,
+            None => return 0,
+        };
         if total == self.stop {
             100
         } else {
@@ -100,8 +142,8 @@ impl ProcessorTrait for Processor {
 
     /// buffered output;
     /// can be called after each tick or when process ends; each call clears the buffer
-    fn output(&mut self) -> Output {
-        std::mem::take(&mut self.buffer)
+    fn output(&mut self) -> Result<Output, Error> {
+        Ok(std::mem::take(&mut self.buffer))
     }
 
 }
@@ -109,7 +151,7 @@ impl ProcessorTrait for Processor {
 impl Processor {
     fn process_cluster(&self, view: &ClustersView, cluster: &Cluster) -> Option<OutputUnit> {
         let path = cluster.to_compound_path(
-            &view, false, PathSimplifyMode::None,
+            view, false, PathSimplifyMode::None,
             0.0, 0.0, 0, 0.0
         );
         let ratio = 1.0 - self.params.shape_details as f64 / Params::MAX_SHAPE_DETAILS as f64;
@@ -125,9 +167,13 @@ impl Processor {
             // patches should expand more at higher ratio
             let outset_ratio = interp(ratio, 0.0, 0.75, 8.0, 4.0);
             let smoothed = simplified.smooth(corner_threshold, outset_ratio, max(4.0, threshold * 0.5));
+            let path = match self.params.path_mode {
+                PathMode::Polygon => smoothed,
+                PathMode::Spline => to_splines(smoothed, corner_threshold),
+            };
 
             Some(OutputUnit {
-                path: smoothed,
+                path,
                 color: cluster.residue_color(),
             })
         } else {
@@ -147,6 +193,112 @@ impl Processor {
         }
         (background, midground)
     }
+
+    /// serialize shapes output by this Processor into a standalone SVG document;
+    /// see [`to_svg_document`]
+    pub fn to_svg(&self, shapes: &[OutputUnit], precision: Option<u32>) -> Result<String, Error> {
+        let clusters = self.clusters.as_ref().ok_or(Error::WrongStage("no input"))?;
+        let (background, _) = self.get_background();
+        Ok(to_svg_document(shapes, clusters.width, clusters.height, &background, precision))
+    }
+}
+
+/// Curve-fit every (smoothed) polyline of `path` into a [`visioncortex::Spline`],
+/// unless the polyline is the more compact of the two
+fn to_splines(path: CompoundPath, corner_threshold: f64) -> CompoundPath {
+    let mut splines = CompoundPath::new();
+    for element in path.paths.into_iter() {
+        match element {
+            CompoundPathElement::PathF64(path) => {
+                let spline = curve_fit::fit_closed(&path, corner_threshold, Params::CURVE_TOLERANCE);
+                // angular outlines are cheaper to keep as polygons
+                if spline.len() < path.len() {
+                    splines.add_spline(spline);
+                } else {
+                    splines.add_path_f64(path);
+                }
+            },
+            other => splines.paths.push(other),
+        }
+    }
+    splines
+}
+
+/// Serialize shapes into a standalone SVG document of `width` x `height`.
+/// The shapes are painted over a `background` fill in the given (stacking) order.
+/// Coordinates are rounded to `precision` decimal places if specified.
+pub fn to_svg_document(shapes: &[OutputUnit], width: u32, height: u32, background: &Color, precision: Option<u32>) -> String {
+    let mut svg = String::new();
+    writeln!(svg, r#"<?xml version="1.0" encoding="UTF-8"?>"#).unwrap();
+    writeln!(
+        svg,
+        r#"<svg version="1.1" xmlns="http://www.w3.org/2000/svg" width="{w}" height="{h}" viewBox="0 0 {w} {h}">"#,
+        w = width, h = height,
+    ).unwrap();
+    writeln!(svg, r#"<rect width="{}" height="{}" fill="{}"/>"#, width, height, background.to_hex_string()).unwrap();
+    for shape in shapes.iter() {
+        writeln!(
+            svg,
+            r#"<path d="{}" fill="{}"/>"#,
+            to_svg_path(&shape.path, precision), shape.color.to_hex_string(),
+        ).unwrap();
+    }
+    writeln!(svg, "</svg>").unwrap();
+    svg
+}
+
+/// absolute path data of closed shapes
+fn to_svg_path(path: &CompoundPath, precision: Option<u32>) -> String {
+    let mut string = String::new();
+    for element in path.iter() {
+        let start = string.len();
+        match element {
+            CompoundPathElement::PathI32(p) => {
+                // the last point repeats the first point
+                for (i, q) in p.iter().take(p.len().saturating_sub(1)).enumerate() {
+                    write_point(&mut string, if i == 0 { "M" } else { "L" }, q.x as f64, q.y as f64, precision);
+                }
+            },
+            CompoundPathElement::PathF64(p) => {
+                for (i, q) in p.iter().take(p.len().saturating_sub(1)).enumerate() {
+                    write_point(&mut string, if i == 0 { "M" } else { "L" }, q.x, q.y, precision);
+                }
+            },
+            CompoundPathElement::Spline(s) => {
+                for (i, q) in s.iter().enumerate() {
+                    let command = match i {
+                        0 => "M",
+                        i if i % 3 == 1 => "C",
+                        _ => "",
+                    };
+                    write_point(&mut string, command, q.x, q.y, precision);
+                }
+            },
+        }
+        if string.len() > start {
+            string.push_str("Z ");
+        }
+    }
+    string.truncate(string.trim_end().len());
+    string
+}
+
+fn write_point(string: &mut String, command: &str, x: f64, y: f64, precision: Option<u32>) {
+    write!(string, "{}{},{} ", command, format_number(x, precision), format_number(y, precision)).unwrap();
+}
+
+fn format_number(x: f64, precision: Option<u32>) -> String {
+    let mut string = match precision {
+        Some(precision) => format!("{:.*}", precision as usize, x),
+        None => format!("{}", x),
+    };
+    if string.contains('.') {
+        string.truncate(string.trim_end_matches('0').trim_end_matches('.').len());
+    }
+    if string == "-0" {
+        string.remove(0);
+    }
+    string
 }
 
 fn interp(x: f64, x0: f64, x1: f64, y0: f64, y1: f64) -> f64 {
@@ -0,0 +1,193 @@
+//! Statistics of the pixels of a region beyond their mean color, and a merge predicate over them
+use visioncortex::color_clusters::ClustersView;
+use visioncortex::{Color, ColorImage};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+use crate::Error;
+
+/// Bins per channel of [`RegionStats::histogram`]
+pub const HISTOGRAM_BINS: usize = 4;
+/// Length of [`RegionStats::histogram`]
+pub const HISTOGRAM_LEN: usize = HISTOGRAM_BINS * HISTOGRAM_BINS * HISTOGRAM_BINS;
+
+/// Color distribution of the pixels of a region
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct RegionStats {
+    /// Number of pixels
+    pub count: u64,
+    /// Sum of r, g and b
+    pub sum: [u64; 3],
+    /// Sum of products of channels, in the order rr, rg, rb, gg, gb, bb
+    pub products: [u64; 6],
+    /// Number of pixels in each color bin, indexed by `(r * HISTOGRAM_BINS + g) * HISTOGRAM_BINS + b`
+    #[cfg_attr(feature = "serde", serde(with = "crate::serde_remote::histogram"))]
+    pub histogram: [u32; HISTOGRAM_LEN],
+}
+
+/// Statistical Region Merging predicate, after R. Nock and F. Nielsen, "Statistical Region
+/// Merging" (IEEE TPAMI, 2004), with allowance for texture. Two regions are alike if the mean
+/// of every channel differs by no more than the error expected of regions of their size, plus
+/// `texture` times their own standard deviation, and their histograms are close enough
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Srm {
+    /// Q in the paper; larger gives more and smaller regions
+    pub complexity: f64,
+    /// Allowed difference in mean, in standard deviations of the regions' pixels; 0 for the
+    /// original predicate
+    pub texture: f64,
+    /// Largest Hellinger distance (0~1) between the histograms of the regions; 1 to disable
+    pub histogram: f64,
+}
+
+impl Default for RegionStats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RegionStats {
+    pub fn new() -> Self {
+        Self {
+            count: 0,
+            sum: [0; 3],
+            products: [0; 6],
+            histogram: [0; HISTOGRAM_LEN],
+        }
+    }
+
+    /// of the pixels at `indices` of an RGBA image
+    pub fn from_pixels(pixels: &[u8], indices: &[u32]) -> Self {
+        let mut stats = Self::new();
+        for &i in indices.iter() {
+            let p = &pixels[i as usize * 4..];
+            stats.add(&Color::new(p[0], p[1], p[2]));
+        }
+        stats
+    }
+
+    pub fn add(&mut self, color: &Color) {
+        let c = [color.r as u64, color.g as u64, color.b as u64];
+        self.count += 1;
+        for (sum, c) in self.sum.iter_mut().zip(c.iter()) {
+            *sum += c;
+        }
+        for (product, (i, j)) in self.products.iter_mut().zip(PAIRS.iter()) {
+            *product += c[*i] * c[*j];
+        }
+        let bin = |v: u8| v as usize * HISTOGRAM_BINS / 256;
+        self.histogram[(bin(color.r) * HISTOGRAM_BINS + bin(color.g)) * HISTOGRAM_BINS + bin(color.b)] += 1;
+    }
+
+    pub fn merge(&mut self, other: &Self) {
+        self.count += other.count;
+        for i in 0..3 {
+            self.sum[i] += other.sum[i];
+        }
+        for i in 0..6 {
+            self.products[i] += other.products[i];
+        }
+        for (bin, other) in self.histogram.iter_mut().zip(other.histogram.iter()) {
+            *bin += other;
+        }
+    }
+
+    /// mean of r, g and b
+    pub fn mean(&self) -> [f64; 3] {
+        let n = std::cmp::max(self.count, 1) as f64;
+        [self.sum[0] as f64 / n, self.sum[1] as f64 / n, self.sum[2] as f64 / n]
+    }
+
+    /// covariance matrix of r, g and b
+    pub fn covariance(&self) -> [[f64; 3]; 3] {
+        let n = std::cmp::max(self.count, 1) as f64;
+        let mean = self.mean();
+        let mut covariance = [[0.0; 3]; 3];
+        for (product, &(i, j)) in self.products.iter().zip(PAIRS.iter()) {
+            let mut value = *product as f64 / n - mean[i] * mean[j];
+            if i == j {
+                // against rounding
+                value = value.max(0.0);
+            }
+            covariance[i][j] = value;
+            covariance[j][i] = value;
+        }
+        covariance
+    }
+
+    /// variance of r, g and b
+    pub fn variance(&self) -> [f64; 3] {
+        let covariance = self.covariance();
+        [covariance[0][0], covariance[1][1], covariance[2][2]]
+    }
+
+    /// Hellinger distance between the normalized histograms, from 0 (identical) to 1 (disjoint)
+    pub fn histogram_distance(&self, other: &Self) -> f64 {
+        if self.count == 0 || other.count == 0 {
+            return 0.0;
+        }
+        let total = (self.count * other.count) as f64;
+        let coefficient: f64 = self.histogram.iter().zip(other.histogram.iter())
+            .map(|(a, b)| ((*a as u64 * *b as u64) as f64).sqrt())
+            .sum::<f64>() / total.sqrt();
+        (1.0 - coefficient).max(0.0).sqrt()
+    }
+}
+
+/// channel pairs of [`RegionStats::products`]
+const PAIRS: [(usize, usize); 6] = [(0, 0), (0, 1), (0, 2), (1, 1), (1, 2), (2, 2)];
+
+impl Default for Srm {
+    fn default() -> Self {
+        Self {
+            complexity: 32.0,
+            texture: 0.5,
+            histogram: 1.0,
+        }
+    }
+}
+
+impl Srm {
+    pub(crate) fn validate(&self) -> Result<(), Error> {
+        let finite = self.complexity.is_finite() && self.texture.is_finite();
+        if !(finite && self.complexity > 0.0 && self.texture >= 0.0 && (0.0..=1.0).contains(&self.histogram)) {
+            return Err(Error::InvalidParams("srm"));
+        }
+        Ok(())
+    }
+
+    /// whether two regions of an image of `area` pixels are alike
+    pub fn test(&self, a: &RegionStats, b: &RegionStats, area: usize) -> bool {
+        let (mean_a, mean_b) = (a.mean(), b.mean());
+        let (variance_a, variance_b) = (a.variance(), b.variance());
+        let bound = (self.bound(a.count, area) + self.bound(b.count, area)).sqrt();
+        for c in 0..3 {
+            let allowance = self.texture * ((variance_a[c] + variance_b[c]) / 2.0).sqrt();
+            if (mean_a[c] - mean_b[c]).abs() > bound + allowance {
+                return false;
+            }
+        }
+        self.histogram >= 1.0 || a.histogram_distance(b) <= self.histogram
+    }
+
+    /// b(R)² in the paper, for a region of `count` pixels; with δ = 1 / (6 |I|²) and
+    /// |R_|R|| ≤ (|R| + 1)^min(|R|, g)
+    fn bound(&self, count: u64, area: usize) -> f64 {
+        const G: f64 = 256.0;
+        let count = std::cmp::max(count, 1) as f64;
+        let ln = count.min(G) * (count + 1.0).ln() + (6.0 * (area as f64).powi(2)).ln();
+        G * G * ln / (2.0 * self.complexity * count)
+    }
+}
+
+/// RGBA pixels to measure an input on: `source` if given, or else the input's own
+pub(crate) fn source_pixels<'a>(source: Option<&'a ColorImage>, view: &ClustersView<'a>) -> Result<&'a [u8], Error> {
+    match source {
+        Some(source) if source.width != view.width as usize || source.height != view.height as usize => {
+            Err(Error::InvalidParams("source"))
+        },
+        Some(source) => Ok(&source.pixels),
+        None => Ok(view.pixels),
+    }
+}
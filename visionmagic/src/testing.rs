@@ -0,0 +1,114 @@
+//! Fixtures shared by the unit tests
+use visioncortex::{BoundingRect, Color, ColorImage};
+use visioncortex::color_clusters::Clusters;
+
+use crate::{Clustering, Processor};
+use crate::region::RegionMap;
+
+/// Blocks of random colors with a little noise on every pixel, the same for the same `seed`
+pub(crate) fn image(width: usize, height: usize, seed: u64) -> ColorImage {
+    const BLOCK: usize = 8;
+    let mut random = Random(seed.wrapping_mul(0x9e3779b97f4a7c15) | 1);
+    let columns = width.div_ceil(BLOCK);
+    let blocks: Vec<[u8; 3]> = (0..columns * height.div_ceil(BLOCK))
+        .map(|_| [random.byte(), random.byte(), random.byte()])
+        .collect();
+    let mut image = ColorImage::new_w_h(width, height);
+    for y in 0..height {
+        for x in 0..width {
+            let block = blocks[(y / BLOCK) * columns + x / BLOCK];
+            let mut noise = || (random.byte() % 9) as i32 - 4;
+            let channel = |c: u8, noise: i32| (c as i32 + noise).clamp(0, 255) as u8;
+            image.set_pixel(x, y, &Color::new(
+                channel(block[0], noise()), channel(block[1], noise()), channel(block[2], noise()),
+            ));
+        }
+    }
+    image
+}
+
+/// hierarchical clusters of `image`, as fed to segmentation and aggregation
+pub(crate) fn clusters(image: ColorImage) -> Clusters {
+    let mut clustering = Clustering::new();
+    clustering.config(crate::clustering::Params {
+        hierarchical: 64,
+        ..Default::default()
+    }).unwrap();
+    clustering.input(image).unwrap();
+    while !clustering.tick() {}
+    clustering.output().unwrap()
+}
+
+/// Side of the blocks of [`blocks`]
+const BLOCK: usize = 8;
+
+/// Flat blocks of 8 by 8 pixels, a row of blocks per row of `colors`
+pub(crate) fn blocks(colors: &[&[[u8; 3]]]) -> ColorImage {
+    let mut image = ColorImage::new_w_h(colors[0].len() * BLOCK, colors.len() * BLOCK);
+    for y in 0..image.height {
+        for x in 0..image.width {
+            let [r, g, b] = colors[y / BLOCK][x / BLOCK];
+            image.set_pixel(x, y, &Color::new(r, g, b));
+        }
+    }
+    image
+}
+
+/// clusters of an image of [`blocks`], one per group of adjacent blocks of the same color
+pub(crate) fn block_clusters(image: ColorImage) -> Clusters {
+    let mut clustering = Clustering::new();
+    clustering.config(crate::clustering::Params {
+        // merges away the fragments that clustering leaves along the borders of the image
+        hierarchical: 16,
+        ..Default::default()
+    }).unwrap();
+    clustering.input(image).unwrap();
+    while !clustering.tick() {}
+    clustering.output().unwrap()
+}
+
+/// assert that every block of an image of [`blocks`] is a region of its own, of the block's color
+pub(crate) fn assert_block_regions(map: &RegionMap, colors: &[&[[u8; 3]]]) {
+    assert_eq!((map.labels.width, map.labels.height), (colors[0].len() * BLOCK, colors.len() * BLOCK));
+    assert_eq!(map.regions.len(), colors.len() * colors[0].len());
+    for (row, blocks) in colors.iter().enumerate() {
+        for (column, &[r, g, b]) in blocks.iter().enumerate() {
+            let (left, top) = (column * BLOCK, row * BLOCK);
+            let id = map.labels.get_pixel(left, top);
+            for y in top..top + BLOCK {
+                for x in left..left + BLOCK {
+                    assert_eq!(map.labels.get_pixel(x, y), id, "({}, {})", x, y);
+                }
+            }
+            let region = &map.regions[id as usize];
+            assert_eq!(region.id, id);
+            assert_eq!(region.area, BLOCK * BLOCK);
+            assert_eq!((region.color.r, region.color.g, region.color.b), (r, g, b));
+            assert_eq!(region.rect, BoundingRect::new_x_y_w_h(left as i32, top as i32, BLOCK as i32, BLOCK as i32));
+        }
+    }
+}
+
+/// `snapshot` after a round trip through serialization where available
+#[cfg(feature = "serde")]
+pub(crate) fn persist<T: serde::Serialize + serde::de::DeserializeOwned>(snapshot: T) -> T {
+    serde_json::from_str(&serde_json::to_string(&snapshot).unwrap()).unwrap()
+}
+
+/// `snapshot` after a round trip through serialization where available
+#[cfg(not(feature = "serde"))]
+pub(crate) fn persist<T>(snapshot: T) -> T {
+    snapshot
+}
+
+/// xorshift64
+struct Random(u64);
+
+impl Random {
+    fn byte(&mut self) -> u8 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 >> 32) as u8
+    }
+}
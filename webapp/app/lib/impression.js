@@ -66,9 +66,25 @@ if (document.getElementById('export')) {
     }, false);
 }
 
-if (document.getElementById('export-svg')) {
-    document.getElementById('export-svg').addEventListener('click', function (e) {
-        const blob = new Blob([new XMLSerializer().serializeToString(svg)], {type: 'octet/stream'}),
+const exportSvg = document.getElementById('export-svg');
+
+// Only offer the SVG once a run has finished
+function enableExportSvg(enabled) {
+    if (exportSvg) {
+        exportSvg.classList.toggle('uk-disabled', !enabled);
+    }
+}
+
+enableExportSvg(false);
+
+if (exportSvg) {
+    exportSvg.addEventListener('click', function (e) {
+        const data = runner && runner.runner.to_svg ? runner.runner.to_svg() : new XMLSerializer().serializeToString(svg);
+        if (data === undefined) {
+            e.preventDefault();
+            return;
+        }
+        const blob = new Blob([data], {type: 'octet/stream'}),
         url = window.URL.createObjectURL(blob);
 
         this.href = url;
@@ -205,10 +221,23 @@ function restart() {
     ctx.filter = 'none';
     progress.value = 0;
     progressregion.style.display = 'block';
-    runner = new Runner(getParams());
+    enableExportSvg(false);
+    try {
+        runner = new Runner(getParams());
+    } catch (error) {
+        runner = null;
+        fail(error);
+        return;
+    }
     runner.run();
 }
 
+function fail(error) {
+    progressregion.style.display = 'none';
+    progress.value = 0;
+    console.error(error);
+}
+
 function clearSvg(svg) {
     while (svg.firstChild) {
         svg.removeChild(svg.firstChild);
@@ -220,7 +249,13 @@ function reconfig() {
         return;
     }
     clearSvg(svg);
-    runner.reconfig(getParams());
+    enableExportSvg(false);
+    try {
+        runner.reconfig(getParams());
+    } catch (error) {
+        fail(error);
+        return;
+    }
     runner.run();
 }
 
@@ -243,10 +278,12 @@ class Runner {
     run () {
         const This = this;
         This.timer = setTimeout(function tick () {
-            let done = false;
-            const startTick = performance.now();
-            while (!(done = This.runner.tick()) &&
-                performance.now() - startTick < 25) {
+            let done;
+            try {
+                done = This.runner.tick_for(25);
+            } catch (error) {
+                fail(error);
+                return;
             }
             progress.value = This.runner.progress();
             if (progress.value >= progress.max) {
@@ -257,6 +294,7 @@ class Runner {
                 This.timer = setTimeout(tick, 1);
             } else {
                 renderWatermark();
+                enableExportSvg(true);
             }
         }, 1);
     }
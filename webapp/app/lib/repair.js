@@ -216,11 +216,7 @@ class Runner {
     run () {
         const This = this;
         This.timer = setTimeout(function tick () {
-            let done = false;
-            const startTick = performance.now();
-            while (!(done = This.runner.tick()) &&
-                performance.now() - startTick < 25) {
-            }
+            const done = This.runner.tick_for(25);
             progress.value = This.runner.progress();
             if (progress.value >= progress.max) {
                 progressregion.style.display = 'none';
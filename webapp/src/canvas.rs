@@ -1,4 +1,4 @@
-use wasm_bindgen::{JsCast, JsValue};
+use wasm_bindgen::JsCast;
 use web_sys::{CanvasRenderingContext2d, Path2d, HtmlCanvasElement, ImageData};
 use visionmagic::visioncortex::{Color, ColorImage, CompoundPath, PointF64};
 
@@ -61,13 +61,13 @@ impl Canvas {
     pub fn render_color_image(&mut self, image: &mut ColorImage, x: u32, y: u32) {
         let image =
             ImageData::new_with_u8_clamped_array_and_sh(
-                wasm_bindgen::Clamped(&mut image.pixels), image.width as u32, image.height as u32
+                wasm_bindgen::Clamped(&image.pixels), image.width as u32, image.height as u32
             ).unwrap();
         self.cctx.put_image_data(&image, x as f64, y as f64).unwrap();
     }
 
     pub fn set_fill_style(&mut self, color: &Color) {
-        self.cctx.set_fill_style(JsValue::from_str(color.to_color_string().as_ref()).as_ref());
+        self.cctx.set_fill_style_str(color.to_color_string().as_ref());
     }
 
     pub fn fill_path(&mut self, paths: &CompoundPath, color: &Color) {
@@ -4,4 +4,9 @@ pub fn window() -> web_sys::Window {
 
 pub fn document() -> web_sys::Document {
     window().document().unwrap()
-}
\ No newline at end of file
+}
+
+/// a visionmagic error, to be thrown in JS
+pub fn js_error(error: visionmagic::Error) -> wasm_bindgen::JsValue {
+    wasm_bindgen::JsValue::from_str(&error.to_string())
+}
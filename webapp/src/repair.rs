@@ -1,7 +1,7 @@
 use wasm_bindgen::prelude::*;
 use visionmagic::visioncortex::ColorImage;
 use visionmagic::fmm::{painter::Painter, smoother::Smoother};
-use std::{u32, u8};
+use std::time::Duration;
 
 use crate::{canvas::*};
 
@@ -59,21 +59,31 @@ impl Repair {
     }
 
     pub fn tick(&mut self) -> bool {
-        let mut temp_paint = std::mem::take(&mut self.painter);
-        temp_paint = temp_paint.paint();
-        let result = Smoother::new( temp_paint.im.buf.clone(), self.image_frame.width as u32, self.image_frame.height as u32, self.blurriness).smooth(&self.buf_mask);
+        self.painter = std::mem::take(&mut self.painter).paint();
+        self.render();
+        self.painter.progress == 100
+    }
+
+    /// paint for up to `budget_ms` milliseconds, then render once; returns true when finished
+    pub fn tick_for(&mut self, budget_ms: u32) -> bool {
+        let budget = Duration::from_millis(budget_ms as u64);
+        self.painter = std::mem::take(&mut self.painter).paint_for(budget);
+        self.render();
+        self.painter.progress == 100
+    }
+
+    pub fn progress(&self) -> u32 {
+        self.painter.progress
+    }
+
+    fn render(&mut self) {
+        let result = Smoother::new(self.painter.im.buf.clone(), self.image_frame.width as u32, self.image_frame.height as u32, self.blurriness).smooth(&self.buf_mask);
         let mut final_result = ColorImage {
             pixels: self.rgb_to_rgba(&result.im.buf),
             width: result.im.width as usize,
             height: result.im.height as usize,
         };
         self.frame.render_color_image(&mut final_result, 0, 0);
-        self.painter = temp_paint;
-        self.painter.progress == 100
-    }
-
-    pub fn progress(&self) -> u32 {
-        self.painter.progress
     }
 
     fn prepare_image(&mut self) {
@@ -98,18 +108,18 @@ impl Repair {
         self.mask.get_image_data_as_color_image(0, 0, width, height)
     }
 
-    fn rgb_to_rgba(&mut self, pixels: &Vec<u8>) -> Vec<u8> { // 24 bits -> 32 bits
+    fn rgb_to_rgba(&mut self, pixels: &[u8]) -> Vec<u8> { // 24 bits -> 32 bits
         let mut result: Vec<u8> = Vec::with_capacity(self.buf_mask.len());
         let length_24 = self.buf_mask.len() * 3 / 4;
-        for i in 0..length_24 {
+        for (i, &pixel) in pixels.iter().enumerate().take(length_24) {
             if result.len() >= self.buf_mask.len() {
                 break;
             }
             if i == 0 || i % 3 != 0 {
-                result.push(pixels[i]);
+                result.push(pixel);
             } else {
                 result.push(255);
-                result.push(pixels[i]);
+                result.push(pixel);
             }
         }
         result.push(255);
@@ -1,8 +1,12 @@
+use std::time::Duration;
 use wasm_bindgen::prelude::*;
 use visionmagic::visioncortex::ColorImage;
-use visionmagic::{Processor, Clustering, Segmentation as Segmenter, Aggregation};
+use visionmagic::clock::{self, SystemClock};
+use visionmagic::{Processor, Chain, Clustering, Segmentation as Segmenter, Aggregation};
+use visionmagic::segmentation::Scribble;
 
 use crate::canvas::*;
+use crate::common::js_error;
 
 use serde::Deserialize;
 
@@ -16,27 +20,28 @@ pub struct SegmentationParams {
     pub min_size: u32,
 }
 
-enum Stage {
-    New,
-    Clustering(Clustering),
-    Segmenter(Segmenter),
-    Reclustering(Clustering),
-    Aggregation(Aggregation),
-}
+/// relative cost of clustering, segmenter, reclustering and aggregation, as measured on the samples
+const STAGE_WEIGHTS: [u32; 4] = [85, 3, 8, 4];
 
-impl Default for Stage {
-    /// the default setting makes no simplification
-    fn default() -> Self {
-        Self::New
-    }
+type Pipeline = Chain<Chain<Chain<Clustering, Segmenter>, Clustering>, Aggregation>;
+
+/// the stage a [`Pipeline`] is running
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum Stage {
+    Clustering,
+    Segmenter,
+    Reclustering,
+    Aggregation,
 }
 
 #[wasm_bindgen]
 pub struct Segmentation {
     canvas: Canvas,
-    stage: Stage,
-    segmenter: Segmenter,
+    pipeline: Option<Pipeline>,
     params: SegmentationParams,
+    scribbles: Vec<Scribble>,
+    /// scribbles changed since the segmenter was last configured
+    scribbles_changed: bool,
 }
 
 impl Segmentation {
@@ -44,9 +49,10 @@ impl Segmentation {
         let canvas = Canvas::new_from_id(&params.canvas_id);
         Self {
             canvas,
-            stage: Stage::New,
-            segmenter: Segmenter::new(),
+            pipeline: None,
             params,
+            scribbles: Vec::new(),
+            scribbles_changed: false,
         }
     }
 }
@@ -59,37 +65,56 @@ impl Segmentation {
         Self::new(params)
     }
 
-    pub fn reconfig(&mut self, params: String) {
-        self.params = serde_json::from_str(params.as_str()).unwrap();
-        let clustering_params = self.clustering_params();
+    /// once segmented, only aggregation re-runs, unless `deviation` (which the segmenter shares)
+    /// or the scribbles have changed, in which case segmentation re-runs as well
+    pub fn reconfig(&mut self, params: String) -> Result<(), JsValue> {
+        let previous = std::mem::replace(&mut self.params, serde_json::from_str(params.as_str()).unwrap());
         let segmenter_params = self.segmenter_params();
-        match &mut self.stage {
-            Stage::New => panic!("uninitialized"),
-            Stage::Clustering(clustering) => {
-                clustering.config(clustering_params);
-            },
-            Stage::Segmenter(segmenter) => {
-                segmenter.config(segmenter_params);
+        let aggregation_params = self.aggregation_params();
+        let scribbles_changed = std::mem::replace(&mut self.scribbles_changed, false);
+        let segmenter_changed = scribbles_changed || previous.deviation != self.params.deviation;
+        let pipeline = self.pipeline.as_mut().expect("uninitialized");
+        match stage_of(pipeline) {
+            Stage::Clustering | Stage::Segmenter => {
+                segmenter_of(pipeline).config(segmenter_params).map_err(js_error)?;
             },
-            Stage::Reclustering(_) | Stage::Aggregation(_) => {
-                let mut segmenter = std::mem::take(&mut self.segmenter);
-                segmenter.config(segmenter_params);
-                self.stage = Stage::Segmenter(segmenter);
+            Stage::Reclustering | Stage::Aggregation if segmenter_changed => {
+                pipeline.rewind();
+                pipeline.first_mut().rewind();
+                segmenter_of(pipeline).config(segmenter_params).map_err(js_error)?;
             },
+            Stage::Reclustering | Stage::Aggregation => (),
         }
+        pipeline.second_mut().config(aggregation_params).map_err(js_error)
+    }
+
+    /// mark pixels as one object; `points` are flattened (x, y) pairs.
+    /// Takes effect on the next `reconfig`
+    pub fn add_scribble(&mut self, label: u32, points: Vec<u32>) {
+        let points = points.chunks_exact(2).map(|pair| (pair[0], pair[1])).collect();
+        self.scribbles.push(Scribble::new(label, points));
+        self.scribbles_changed = true;
+    }
+
+    /// takes effect on the next `reconfig`
+    pub fn clear_scribbles(&mut self) {
+        self.scribbles.clear();
+        self.scribbles_changed = true;
     }
 
     fn clustering_params(&self) -> <Clustering as Processor>::Params {
         type Params = <Clustering as Processor>::Params;
-        let mut params = Params::default();
-        params.hierarchical = 64;
-        params
+        Params {
+            hierarchical: 64,
+            ..Default::default()
+        }
     }
 
     fn segmenter_params(&self) -> <Segmenter as Processor>::Params {
         type Params = <Segmenter as Processor>::Params;
         let mut params = Params::default();
         params.deviation = self.params.deviation;
+        params.scribbles = self.scribbles.clone();
         params
     }
 
@@ -98,50 +123,36 @@ impl Segmentation {
         let mut params = Params::default();
         params.deviation = self.params.deviation;
         params.min_size = self.params.min_size;
+        // that of the reclustering before it, as the clusters do not record it
+        params.connectivity = self.clustering_params().connectivity;
         params
     }
 
-    pub fn init(&mut self) {
-        self.prepare_clustering();
+    pub fn init(&mut self) -> Result<(), JsValue> {
+        self.prepare_clustering()
     }
 
-    pub fn tick(&mut self) -> bool {
-        match &mut self.stage {
-            Stage::New => panic!("uninitialized"),
-            Stage::Clustering(processor) => {
-                if processor.tick() {
-                    self.prepare_segmenter();
-                }
-                false
-            },
-            Stage::Segmenter(processor) => {
-                if processor.tick() {
-                    self.prepare_reclustering();
-                }
-                false
-            },
-            Stage::Reclustering(processor) => {
-                if processor.tick() {
-                    self.prepare_aggregation();
-                }
-                false
-            },
-            Stage::Aggregation(processor) => {
-                if processor.tick() {
-                    self.aggregation_output();
-                    return true;
-                }
-                false
-            },
+    pub fn tick(&mut self) -> Result<bool, JsValue> {
+        if self.pipeline.as_mut().expect("uninitialized").tick() {
+            self.aggregation_output()?;
+            return Ok(true);
         }
+        Ok(false)
+    }
+
+    /// keep ticking for up to `budget_ms` milliseconds; returns true when finished
+    pub fn tick_for(&mut self, budget_ms: u32) -> Result<bool, JsValue> {
+        let budget = Duration::from_millis(budget_ms as u64);
+        let mut result = Ok(false);
+        clock::run_for(budget, &SystemClock::new(), || {
+            result = self.tick();
+            result.as_ref().map_or(true, |done| *done)
+        });
+        result
     }
 
     pub fn progress(&self) -> u32 {
-        match &self.stage {
-            Stage::New => 0,
-            Stage::Clustering(processor) => processor.progress(),
-            _ => 100,
-        }
+        self.pipeline.as_ref().map_or(0, |pipeline| pipeline.progress())
     }
 
     fn get_image_from_canvas(&self) -> ColorImage {
@@ -150,62 +161,44 @@ impl Segmentation {
         self.canvas.get_image_data_as_color_image(0, 0, width, height)
     }
 
-    fn prepare_clustering(&mut self) {
+    fn prepare_clustering(&mut self) -> Result<(), JsValue> {
         let image = self.get_image_from_canvas();
-        let mut clustering = Clustering::new();
-        clustering.config(self.clustering_params());
-        clustering.input(image);
-        self.stage = Stage::Clustering(clustering);
-    }
-
-    fn prepare_segmenter(&mut self) {
-        let mut segmenter = Segmenter::new();
-        let stage = std::mem::take(&mut self.stage);
-        if let Stage::Clustering(mut clustering) = stage {
-            segmenter.config(self.segmenter_params());
-            segmenter.input(clustering.output());
-        } else {
-            panic!("must be in Stage::Clustering")
-        }
-        self.stage = Stage::Segmenter(segmenter);
-    }
-
-    fn prepare_reclustering(&mut self) {
-        type Params = <Clustering as Processor>::Params;
-        let mut clustering = Clustering::new();
-        let mut params = Params::default();
-        params.hierarchical = 64;
-        let stage = std::mem::take(&mut self.stage);
-        if let Stage::Segmenter(mut segmenter) = stage {
-	        clustering.config(params);
-	        clustering.input(segmenter.output());
-            self.segmenter = segmenter;
-        } else {
-            panic!("must be in Stage::Segmenter")
-        }
-        self.stage = Stage::Reclustering(clustering);
+        let [clustering, segmenter, reclustering, aggregation] = STAGE_WEIGHTS;
+        let mut pipeline = Clustering::new()
+            .chain(Segmenter::new()).with_weights(clustering, segmenter)
+            .chain(Clustering::new()).with_weights(clustering + segmenter, reclustering)
+            .chain(Aggregation::new()).with_weights(clustering + segmenter + reclustering, aggregation);
+        pipeline.config((
+            ((self.clustering_params(), self.segmenter_params()), self.clustering_params()),
+            self.aggregation_params(),
+        )).map_err(js_error)?;
+        pipeline.input(image).map_err(js_error)?;
+        self.scribbles_changed = false;
+        self.pipeline = Some(pipeline);
+        Ok(())
+    }
+
+    fn aggregation_output(&mut self) -> Result<(), JsValue> {
+        let mut image = self.pipeline.as_mut().expect("uninitialized").output().map_err(js_error)?;
+        self.canvas.clear();
+        self.canvas.render_color_image(&mut image, 0, 0);
+        Ok(())
     }
 
-    fn prepare_aggregation(&mut self) {
-        let mut aggregation = Aggregation::new();
-        let stage = std::mem::take(&mut self.stage);
-        if let Stage::Reclustering(mut processor) = stage {
-            aggregation.config(self.aggregation_params());
-            aggregation.input(processor.output());
-        } else {
-            panic!("must be in Stage::Reclustering")
-        }
-        self.stage = Stage::Aggregation(aggregation);
-    }
+}
 
-    fn aggregation_output(&mut self) {
-        if let Stage::Aggregation(processor) = &mut self.stage {
-            let mut image = processor.output();
-            self.canvas.clear();
-            self.canvas.render_color_image(&mut image, 0, 0);
-        } else {
-            panic!("must be in Stage::Aggregation")
-        }
+fn stage_of(pipeline: &Pipeline) -> Stage {
+    if pipeline.in_second_stage() {
+        Stage::Aggregation
+    } else if pipeline.first().in_second_stage() {
+        Stage::Reclustering
+    } else if pipeline.first().first().in_second_stage() {
+        Stage::Segmenter
+    } else {
+        Stage::Clustering
     }
+}
 
-}
\ No newline at end of file
+fn segmenter_of(pipeline: &mut Pipeline) -> &mut Segmenter {
+    pipeline.first_mut().first_mut().second_mut()
+}
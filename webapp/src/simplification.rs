@@ -1,6 +1,9 @@
+use std::time::Duration;
 use wasm_bindgen::prelude::*;
 use visionmagic::visioncortex::ColorImage;
-use visionmagic::{Processor, Clustering, Simplification as Simplifier};
+use visionmagic::clock::{self, SystemClock};
+use visionmagic::simplification::{OutputUnit, PathMode};
+use visionmagic::{Processor, Chain, Clustering, Simplification as Simplifier};
 
 use crate::canvas::*;
 
@@ -16,25 +19,22 @@ pub struct SimplificationParams {
     pub color_levels: u32,
     /// range 1~65536
     pub shape_details: u32,
+    /// fit shapes with Bezier curves instead of polygons
+    #[serde(default)]
+    pub spline: bool,
 }
 
-enum Stage {
-    New,
-    Clustering(Clustering),
-    Simplifier(Simplifier),
-}
+/// relative cost of clustering and simplifier, as measured on the samples
+const STAGE_WEIGHTS: [u32; 2] = [85, 15];
 
-impl Default for Stage {
-    /// the default setting makes no simplification
-    fn default() -> Self {
-        Self::New
-    }
-}
+type Pipeline = Chain<Clustering, Simplifier>;
 
 #[wasm_bindgen]
 pub struct Simplification {
     canvas: Canvas,
-    stage: Stage,
+    pipeline: Option<Pipeline>,
+    /// result of the last finished run
+    shapes: Option<Vec<OutputUnit>>,
     params: SimplificationParams,
 }
 
@@ -43,7 +43,8 @@ impl Simplification {
         let canvas = Canvas::new_from_id(&params.canvas_id);
         Self {
             canvas,
-            stage: Stage::New,
+            pipeline: None,
+            shapes: None,
             params,
         }
     }
@@ -61,15 +62,13 @@ impl Simplification {
         self.params = serde_json::from_str(params.as_str()).unwrap();
         let clustering_params = self.clustering_params();
         let simplifier_params = self.simplifier_params();
-        match &mut self.stage {
-            Stage::New => panic!("uninitialized"),
-            Stage::Clustering(clustering) => {
-                clustering.config(clustering_params);
-            },
-            Stage::Simplifier(simplifier) => {
-                simplifier.config(simplifier_params);
-            },
+        let pipeline = self.pipeline.as_mut().expect("uninitialized");
+        if pipeline.in_second_stage() {
+            pipeline.second_mut().config(simplifier_params).unwrap();
+        } else {
+            pipeline.config((clustering_params, simplifier_params)).unwrap();
         }
+        self.shapes = None;
     }
 
     fn clustering_params(&self) -> <Clustering as Processor>::Params {
@@ -84,6 +83,7 @@ impl Simplification {
         Params {
             fidelity: self.params.fidelity,
             shape_details: self.params.shape_details,
+            path_mode: if self.params.spline { PathMode::Spline } else { PathMode::Polygon },
         }
     }
 
@@ -92,30 +92,27 @@ impl Simplification {
     }
 
     pub fn tick(&mut self) -> bool {
-        match &mut self.stage {
-            Stage::New => panic!("uninitialized"),
-            Stage::Clustering(processor) => {
-                if processor.tick() {
-                    self.prepare_simplify();
-                }
-                false
-            },
-            Stage::Simplifier(processor) => {
-                if processor.tick() {
-                    self.simplifier_output();
-                    return true;
-                }
-                false
-            },
+        if self.pipeline.as_mut().expect("uninitialized").tick() {
+            self.simplifier_output();
+            return true;
         }
+        false
+    }
+
+    /// keep ticking for up to `budget_ms` milliseconds; returns true when finished
+    pub fn tick_for(&mut self, budget_ms: u32) -> bool {
+        let budget = Duration::from_millis(budget_ms as u64);
+        clock::run_for(budget, &SystemClock::new(), || self.tick())
     }
 
     pub fn progress(&self) -> u32 {
-        match &self.stage {
-            Stage::New => 0,
-            Stage::Clustering(processor) => processor.progress(),
-            _ => 100,
-        }
+        self.pipeline.as_ref().map_or(0, |pipeline| pipeline.progress())
+    }
+
+    /// standalone SVG document of the finished result; none until a run has finished
+    pub fn to_svg(&self) -> Option<String> {
+        let shapes = self.shapes.as_ref()?;
+        self.pipeline.as_ref()?.second().to_svg(shapes, Some(2)).ok()
     }
 
     fn get_image_from_canvas(&self) -> ColorImage {
@@ -126,36 +123,23 @@ impl Simplification {
 
     fn prepare_clustering(&mut self) {
         let image = self.get_image_from_canvas();
-        let mut clustering = Clustering::new();
-        clustering.config(self.clustering_params());
-        clustering.input(image);
-        self.stage = Stage::Clustering(clustering);
-    }
-
-    fn prepare_simplify(&mut self) {
-        let mut simplifier = Simplifier::new();
-        simplifier.config(self.simplifier_params());
-        let stage = std::mem::take(&mut self.stage);
-        if let Stage::Clustering(mut clustering) = stage {
-            simplifier.input(clustering.output());
-        } else {
-            panic!("must be in Stage::Clustering")
-        }
-        self.stage = Stage::Simplifier(simplifier);
+        let mut pipeline = Clustering::new().chain(Simplifier::new())
+            .with_weights(STAGE_WEIGHTS[0], STAGE_WEIGHTS[1]);
+        pipeline.config((self.clustering_params(), self.simplifier_params())).unwrap();
+        pipeline.input(image).unwrap();
+        self.pipeline = Some(pipeline);
+        self.shapes = None;
     }
 
     fn simplifier_output(&mut self) {
-        if let Stage::Simplifier(simplifier) = &mut self.stage {
-            let shapes = simplifier.output();
-            for shape in shapes.iter() {
-                self.canvas.fill_path(
-                    &shape.path,
-                    &shape.color,
-                );
-            }
-        } else {
-            panic!("must be in Stage::Simplifier")
+        let shapes = self.pipeline.as_mut().expect("uninitialized").output().unwrap();
+        for shape in shapes.iter() {
+            self.canvas.fill_path(
+                &shape.path,
+                &shape.color,
+            );
         }
+        self.shapes = Some(shapes);
     }
 
 }
\ No newline at end of file